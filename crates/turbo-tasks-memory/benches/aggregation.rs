@@ -0,0 +1,177 @@
+//! Benchmarks the propagation of changes in the aggregation tree.
+//!
+//! Compare runs with criterion baselines, e. g. run
+//! `cargo bench -p turbo-tasks-memory --features test-util --bench aggregation
+//! -- --save-baseline before` before a change and `--baseline before` after
+//! it.
+
+use std::time::Instant;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use turbo_tasks_memory::test_util::{GraphBuilder, TestGraph, UpperKind};
+
+/// A leaf which is the left child of its own bottom tree. Changes are
+/// propagated to that single upper.
+fn single_upper() -> TestGraph {
+    let graph = GraphBuilder::new()
+        .node("parent", 0)
+        .node("leaf", 0)
+        .edge("parent", "leaf")
+        .build();
+    graph.aggregated_value("parent");
+    graph.aggregated_value("leaf");
+    assert_eq!(graph.upper_kind("leaf"), UpperKind::Left);
+    assert_eq!(graph.upper_count("leaf"), 1);
+    graph
+}
+
+/// A leaf which is an inner child of the bottom trees of `parents` nodes.
+fn multiple_uppers(parents: usize) -> TestGraph {
+    let mut builder = GraphBuilder::new().node("leaf", 0);
+    for parent in 0..parents {
+        builder = builder
+            .node(format!("parent{parent}"), 0)
+            .edge(format!("parent{parent}"), "leaf");
+    }
+    let graph = builder.build();
+    for parent in 0..parents {
+        graph.aggregated_value(&format!("parent{parent}"));
+    }
+    assert_eq!(graph.upper_kind("leaf"), UpperKind::Inner);
+    assert_eq!(graph.upper_count("leaf"), parents);
+    graph
+}
+
+fn change_job(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aggregation_change_job");
+
+    let graph = single_upper();
+    group.bench_function("single_upper", |b| {
+        b.iter(|| graph.change_job("leaf", 1)());
+    });
+    // The same change without the single upper fast path as baseline
+    group.bench_function("single_upper_without_fast_path", |b| {
+        b.iter(|| graph.change_job_without_fast_path("leaf", 1)());
+    });
+
+    for parents in [2, 8, 32] {
+        let graph = multiple_uppers(parents);
+        group.bench_with_input(
+            BenchmarkId::new("multiple_uppers", parents),
+            &graph,
+            |b, graph| {
+                b.iter(|| graph.change_job("leaf", 1)());
+            },
+        );
+    }
+}
+
+/// Changes capture the uppers of a leaf copy-on-write, so a change doesn't
+/// allocate for thousands of uppers. The uppers are only copied when they are
+/// modified while a captured change is still pending.
+fn many_uppers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aggregation_many_uppers");
+    group.sample_size(20);
+
+    for parents in [1024, 4096] {
+        let graph = multiple_uppers(parents);
+        group.bench_with_input(BenchmarkId::new("change", parents), &graph, |b, graph| {
+            b.iter(|| graph.change_job("leaf", 1)());
+        });
+        // Modifying the uppers without a pending change as baseline for the next
+        // one
+        group.bench_with_input(
+            BenchmarkId::new("modify_uppers", parents),
+            &graph,
+            |b, graph| {
+                b.iter(|| {
+                    graph.disconnect("parent0", "leaf");
+                    graph.connect("parent0", "leaf");
+                });
+            },
+        );
+        // Modifying the uppers while the change is pending copies them
+        group.bench_with_input(
+            BenchmarkId::new("change_with_modified_uppers", parents),
+            &graph,
+            |b, graph| {
+                b.iter(|| {
+                    let prepared = graph.prepare_change("leaf", 1);
+                    graph.disconnect("parent0", "leaf");
+                    graph.connect("parent0", "leaf");
+                    prepared.apply(graph.context());
+                });
+            },
+        );
+    }
+}
+
+/// A chain of aggregated nodes with one leaf per thread at its end. All
+/// changes are propagated through the same aggregated nodes. The first node of
+/// the chain has `parents` parents, so changes are propagated to that many
+/// uppers.
+fn shared_chain(threads: usize, parents: usize) -> TestGraph {
+    const DEPTH: usize = 4;
+    let mut builder = GraphBuilder::new();
+    for level in 0..DEPTH {
+        builder = builder.node(format!("level{level}"), 0);
+        if level > 0 {
+            builder = builder.edge(format!("level{}", level - 1), format!("level{level}"));
+        }
+    }
+    for thread in 0..threads {
+        builder = builder
+            .node(format!("leaf{thread}"), 0)
+            .edge(format!("level{}", DEPTH - 1), format!("leaf{thread}"));
+    }
+    for parent in 0..parents {
+        builder = builder
+            .node(format!("parent{parent}"), 0)
+            .edge(format!("parent{parent}"), "level0");
+    }
+    let graph = builder.build();
+    graph.aggregated_value("level0");
+    for parent in 0..parents {
+        graph.aggregated_value(&format!("parent{parent}"));
+    }
+    graph
+}
+
+/// Concurrent changes through shared aggregated nodes. An aggregated node is
+/// locked until a change has been propagated to its uppers, so the changes
+/// are serialized there. The uppers are captured copy-on-write, so this
+/// measures whether a large upper set makes the lock a bottleneck.
+fn concurrent_changes(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aggregation_concurrent_changes");
+
+    for parents in [0, 256] {
+        for threads in [1, 4, 8] {
+            let graph = shared_chain(threads, parents);
+            let leaves = (0..threads)
+                .map(|thread| format!("leaf{thread}"))
+                .collect::<Vec<_>>();
+            group.bench_with_input(
+                BenchmarkId::new(format!("parents_{parents}"), threads),
+                &graph,
+                |b, graph| {
+                    b.iter_custom(|iters| {
+                        let start = Instant::now();
+                        std::thread::scope(|scope| {
+                            for leaf in &leaves {
+                                scope.spawn(move || {
+                                    for i in 0..iters {
+                                        graph.change(leaf, if i % 2 == 0 { 1 } else { -1 });
+                                    }
+                                });
+                            }
+                        });
+                        start.elapsed()
+                    });
+                },
+            );
+        }
+    }
+}
+
+criterion_group!(aggregation, change_job, many_uppers, concurrent_changes);
+criterion_main!(aggregation);
@@ -0,0 +1,156 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use parking_lot::{Condvar, Mutex};
+use turbo_tasks::TurboTasksBackendApi;
+
+use crate::MemoryBackend;
+
+/// When set at startup, the aggregation structure is dumped to this path every
+/// time a file with the same name plus `.trigger` is created. The trigger file
+/// is removed once the dump is written, so it can be created again for the
+/// next dump. This works while tasks are running, e. g. when the process hangs.
+pub const AGGREGATION_DUMP_ENV: &str = "TURBO_TASKS_AGGREGATION_DUMP";
+
+/// How often the trigger file of the aggregation dump is checked.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The path of an aggregation dump and the path of its trigger file.
+struct DumpTrigger {
+    path: PathBuf,
+    trigger: PathBuf,
+}
+
+impl DumpTrigger {
+    fn new(path: PathBuf) -> Self {
+        let mut trigger = path.clone().into_os_string();
+        trigger.push(".trigger");
+        Self {
+            path,
+            trigger: PathBuf::from(trigger),
+        }
+    }
+
+    /// Removes the trigger file. Returns true when it existed. Only the one
+    /// removing it gets true, so every trigger file causes a single dump.
+    fn take(&self) -> bool {
+        std::fs::remove_file(&self.trigger).is_ok()
+    }
+}
+
+/// The thread which dumps the aggregation structure when triggered. It's owned
+/// by the backend and stops when the backend is stopped or dropped.
+#[derive(Default)]
+pub struct AggregationDumpThread {
+    stopped: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl AggregationDumpThread {
+    /// Starts polling for the trigger file of `path`.
+    pub fn start(&self, path: PathBuf, turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>) {
+        let turbo_tasks = Arc::downgrade(&turbo_tasks.pin());
+        self.spawn(DumpTrigger::new(path), move |path| {
+            let Some(turbo_tasks) = turbo_tasks.upgrade() else {
+                return false;
+            };
+            if let Err(err) = turbo_tasks.backend().dump_aggregation_structure(path) {
+                tracing::warn!("failed to dump aggregation structure: {err}");
+            }
+            true
+        });
+    }
+
+    /// Spawns the polling thread. `dump` is called with the path of the dump
+    /// when triggered, and returns false when the thread should exit.
+    fn spawn(
+        &self,
+        trigger: DumpTrigger,
+        mut dump: impl FnMut(&Path) -> bool + Send + 'static,
+    ) -> JoinHandle<()> {
+        let stopped = self.stopped.clone();
+        std::thread::Builder::new()
+            .name("turbo-tasks aggregation dump".to_string())
+            .spawn(move || {
+                let (stopped, condvar) = &*stopped;
+                loop {
+                    {
+                        let mut stopped = stopped.lock();
+                        if !*stopped {
+                            condvar.wait_for(&mut stopped, POLL_INTERVAL);
+                        }
+                        if *stopped {
+                            return;
+                        }
+                    }
+                    if trigger.take() && !dump(&trigger.path) {
+                        return;
+                    }
+                }
+            })
+            .expect("failed to spawn the aggregation dump thread")
+    }
+
+    /// Stops the thread. It exits without waiting for the next poll.
+    pub fn stop(&self) {
+        let (stopped, condvar) = &*self.stopped;
+        *stopped.lock() = true;
+        condvar.notify_all();
+    }
+}
+
+impl Drop for AggregationDumpThread {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    fn dump_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "turbo-tasks-aggregation-dump-{name}-{}.txt",
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn take_trigger() {
+        let trigger = DumpTrigger::new(dump_path("take"));
+        assert!(trigger.trigger.to_string_lossy().ends_with(".txt.trigger"));
+        assert!(!trigger.take());
+
+        std::fs::write(&trigger.trigger, "").unwrap();
+        assert!(trigger.take());
+        assert!(!trigger.trigger.exists());
+        assert!(!trigger.take());
+    }
+
+    #[test]
+    fn dump_on_trigger_until_stopped() {
+        let path = dump_path("thread");
+        let trigger = DumpTrigger::new(path.clone());
+        let trigger_path = trigger.trigger.clone();
+        let thread = AggregationDumpThread::default();
+        let (dumped, dumps) = channel();
+        let handle = thread.spawn(trigger, move |path| {
+            dumped.send(path.to_path_buf()).unwrap();
+            true
+        });
+
+        std::fs::write(&trigger_path, "").unwrap();
+        assert_eq!(dumps.recv().unwrap(), path);
+        assert!(!trigger_path.exists());
+
+        drop(thread);
+        handle.join().unwrap();
+        assert!(dumps.try_recv().is_err());
+    }
+}
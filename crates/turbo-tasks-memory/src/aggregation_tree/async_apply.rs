@@ -0,0 +1,35 @@
+//! Applying changes from async code. Only the item lock might need to wait for
+//! a long time. Propagating a change to the aggregated nodes doesn't lock any
+//! item and only holds the locks of aggregated nodes for a short time.
+
+use std::future::Future;
+
+use super::{AggregationContext, AggregationItemLock};
+
+/// An [AggregationContext] which can lock items asynchronously, e. g. when
+/// items are protected by async locks.
+pub trait AsyncAggregationContext: AggregationContext {
+    /// Gets mutable access to an item without blocking the current thread.
+    fn item_async<'a>(
+        &'a self,
+        reference: &Self::ItemRef,
+    ) -> impl Future<Output = Self::ItemLock<'a>> + 'a;
+}
+
+/// Modifies an item and propagates the resulting change to the aggregated
+/// nodes. `modify` is called while the item is locked and returns the change
+/// of the item. The change is applied after the item lock has been released.
+pub async fn apply_change_async<'a, C: AsyncAggregationContext>(
+    aggregation_context: &'a C,
+    reference: &C::ItemRef,
+    modify: impl FnOnce(&mut C::ItemLock<'a>) -> Option<C::ItemChange>,
+) {
+    let prepared = {
+        let mut item = aggregation_context.item_async(reference).await;
+        let Some(change) = modify(&mut item) else {
+            return;
+        };
+        item.leaf().prepare_change(aggregation_context, change)
+    };
+    prepared.apply(aggregation_context);
+}
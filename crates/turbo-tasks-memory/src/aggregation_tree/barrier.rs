@@ -0,0 +1,176 @@
+//! Changes are propagated synchronously, so a change is visible in all
+//! aggregated nodes once [AggregationTreeLeaf::change] returns. But most
+//! changes are prepared under the item lock and applied later, e. g. the jobs
+//! returned by [AggregationTreeLeaf::change_job] or a [PreparedChange] sent to
+//! another thread. `change_barrier` waits for these.
+//!
+//! Tracking pending changes costs a lock of the pending set of the leaf for
+//! every prepared change, so it's only enabled with the `aggregation_barrier`
+//! feature. Otherwise [PendingChanges] and [PendingChange] are no-ops.
+//!
+//! Pending changes are tracked per leaf, since a change of an item is only
+//! visible in the aggregated nodes which have the item in their subgraph. The
+//! barrier collects the pending sets of the subgraph of the item, so changes in
+//! unrelated parts of the graph don't keep it waiting. Every change gets a
+//! sequence number and the barrier only waits for the changes that have been
+//! prepared before it started, so changes prepared while waiting can't keep it
+//! waiting forever.
+//!
+//! Memory ordering: Preparing a change adds it to the pending set of the leaf
+//! before the item lock is released, so the barrier sees it when it locks the
+//! item. It's removed from the set after the change has been propagated to all
+//! aggregated nodes. The set is protected by a mutex, so everything written by
+//! the change is visible to the barrier once it sees that the change isn't
+//! pending anymore.
+//!
+//! [AggregationTreeLeaf::change]: super::AggregationTreeLeaf::change
+//! [AggregationTreeLeaf::change_job]: super::AggregationTreeLeaf::change_job
+//! [PreparedChange]: super::leaf::PreparedChange
+
+#[cfg(feature = "aggregation_barrier")]
+mod enabled {
+    use std::{
+        collections::{BTreeSet, HashSet},
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+    };
+
+    use parking_lot::{Condvar, Mutex};
+
+    use super::super::{reentrancy, AggregationContext, AggregationItemLock};
+
+    /// The sequence number of the next prepared change.
+    static NEXT_CHANGE: AtomicU64 = AtomicU64::new(0);
+    /// Notified whenever a pending change has been applied. The mutex is taken
+    /// before notifying, so a barrier can't miss a notification between
+    /// checking the pending sets and starting to wait.
+    static APPLIED_LOCK: Mutex<()> = Mutex::new(());
+    static APPLIED: Condvar = Condvar::new();
+
+    type PendingSet = Arc<Mutex<BTreeSet<u64>>>;
+
+    /// The sequence numbers of the changes of a leaf that have been prepared
+    /// but not applied yet.
+    #[derive(Default)]
+    pub struct PendingChanges {
+        set: PendingSet,
+    }
+
+    /// Registers a change as pending in the [PendingChanges] of its leaf until
+    /// it's dropped.
+    pub struct PendingChange {
+        set: PendingSet,
+        number: u64,
+    }
+
+    impl PendingChange {
+        pub fn new(pending: &PendingChanges) -> Self {
+            let number = NEXT_CHANGE.fetch_add(1, Ordering::SeqCst);
+            pending.set.lock().insert(number);
+            Self {
+                set: pending.set.clone(),
+                number,
+            }
+        }
+    }
+
+    impl Drop for PendingChange {
+        fn drop(&mut self) {
+            self.set.lock().remove(&self.number);
+            let _guard = APPLIED_LOCK.lock();
+            APPLIED.notify_all();
+        }
+    }
+
+    /// Returns true when a change with a smaller sequence number than `before`
+    /// is pending.
+    fn has_pending_before(set: &PendingSet, before: u64) -> bool {
+        set.lock().first().is_some_and(|&number| number < before)
+    }
+
+    /// Collects the pending sets of all items in the subgraph of `reference`,
+    /// including the item itself.
+    fn subgraph_pending_sets<C: AggregationContext>(
+        aggregation_context: &C,
+        reference: &C::ItemRef,
+    ) -> Vec<PendingSet> {
+        let mut visited = HashSet::new();
+        visited.insert(reference.clone());
+        let mut queue = vec![reference.clone()];
+        let mut sets = Vec::new();
+        while let Some(reference) = queue.pop() {
+            let mut item = reentrancy::item(aggregation_context, &reference);
+            for child in item.children() {
+                if visited.insert(child.clone().into_owned()) {
+                    queue.push(child.into_owned());
+                }
+            }
+            sets.push(item.leaf().pending_changes().set.clone());
+        }
+        sets
+    }
+
+    /// Waits until all changes in the subgraph of the item that have been
+    /// prepared before the call are applied, so they are visible in the
+    /// aggregated info of all aggregated nodes. Changes of items outside of
+    /// the subgraph are not waited for.
+    ///
+    /// The items of the subgraph are locked one after another, so this must
+    /// not be called while an item is locked, while the current thread
+    /// propagates a change or while the current thread holds a prepared change
+    /// in the subgraph that hasn't been applied, since the barrier would never
+    /// return.
+    pub fn change_barrier<C: AggregationContext>(aggregation_context: &C, reference: &C::ItemRef) {
+        debug_assert!(
+            !reentrancy::is_propagating(),
+            "change_barrier must not be called while propagating a change"
+        );
+        let before = NEXT_CHANGE.load(Ordering::SeqCst);
+        let sets = subgraph_pending_sets(aggregation_context, reference);
+        let mut guard = APPLIED_LOCK.lock();
+        while sets.iter().any(|set| has_pending_before(set, before)) {
+            APPLIED.wait(&mut guard);
+        }
+    }
+
+    /// Returns true when a change in the subgraph of the item is pending, i. e.
+    /// [change_barrier] would wait.
+    #[cfg(test)]
+    pub fn has_pending_changes<C: AggregationContext>(
+        aggregation_context: &C,
+        reference: &C::ItemRef,
+    ) -> bool {
+        let before = NEXT_CHANGE.load(Ordering::SeqCst);
+        subgraph_pending_sets(aggregation_context, reference)
+            .iter()
+            .any(|set| has_pending_before(set, before))
+    }
+}
+
+#[cfg(all(test, feature = "aggregation_barrier"))]
+pub use enabled::has_pending_changes;
+#[cfg(feature = "aggregation_barrier")]
+pub use enabled::{change_barrier, PendingChange, PendingChanges};
+
+/// The changes of a leaf that have been prepared but not applied yet.
+#[cfg(not(feature = "aggregation_barrier"))]
+#[derive(Default)]
+pub struct PendingChanges {
+    _private: (),
+}
+
+/// Registers a change as pending until it's dropped.
+#[cfg(not(feature = "aggregation_barrier"))]
+pub struct PendingChange {
+    _private: (),
+}
+
+#[cfg(not(feature = "aggregation_barrier"))]
+impl PendingChange {
+    #[inline(always)]
+    pub fn new(_pending: &PendingChanges) -> Self {
+        Self { _private: () }
+    }
+}
@@ -6,44 +6,81 @@ use nohash_hasher::{BuildNoHashHasher, IsEnabled};
 use super::{
     bottom_tree::BottomTree,
     inner_refs::{BottomRef, ChildLocation},
-    AggregationContext, StackVec,
+    AggregationContext,
 };
 
+#[derive(Clone)]
 struct BottomRefInfo {
     count: isize,
     distance: u8,
 }
 
+type CountMap<T> = AutoMap<T, BottomRefInfo, BuildNoHashHasher<T>>;
+
 /// A map that stores references to bottom trees which a specific distance. It
 /// stores the minimum distance added to the map.
 ///
 /// This is used to store uppers of leafs or smaller bottom trees with the
 /// current distance. The distance is imporant to keep the correct connectivity.
-#[derive(Default)]
+///
+/// The map is shared copy-on-write. Cloning it to capture the uppers for a
+/// change is cheap and the map is only copied when it's modified while a clone
+/// is still alive.
 pub struct DistanceCountMap<T: IsEnabled> {
-    map: AutoMap<T, BottomRefInfo, BuildNoHashHasher<T>>,
+    map: Option<Arc<CountMap<T>>>,
 }
 
-impl<T: IsEnabled + Eq + Hash + Clone> DistanceCountMap<T> {
-    pub fn new() -> Self {
+impl<T: IsEnabled> Default for DistanceCountMap<T> {
+    fn default() -> Self {
+        Self { map: None }
+    }
+}
+
+impl<T: IsEnabled> Clone for DistanceCountMap<T> {
+    fn clone(&self) -> Self {
         Self {
-            map: AutoMap::with_hasher(),
+            map: self.map.clone(),
         }
     }
+}
+
+impl<T: IsEnabled + Eq + Hash + Clone> DistanceCountMap<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
     pub fn is_unset(&self) -> bool {
-        self.map.is_empty()
+        match &self.map {
+            Some(map) => map.is_empty(),
+            None => true,
+        }
     }
 
     pub fn iter(&self) -> impl Iterator<Item = (&T, u8)> {
         self.map
             .iter()
+            .flat_map(|map| map.iter())
             .filter(|(_, info)| info.count > 0)
             .map(|(item, &BottomRefInfo { distance, .. })| (item, distance))
     }
 
+    /// Returns a pointer which identifies the shared map. Clones of the map
+    /// have the same pointer until one of them is modified.
+    pub fn as_ptr(&self) -> *const () {
+        self.map
+            .as_ref()
+            .map_or(std::ptr::null(), |map| Arc::as_ptr(map).cast())
+    }
+
+    fn map_mut(&mut self) -> &mut CountMap<T> {
+        Arc::make_mut(
+            self.map
+                .get_or_insert_with(|| Arc::new(AutoMap::with_hasher())),
+        )
+    }
+
     pub fn add_clonable(&mut self, item: &T, distance: u8) -> bool {
-        match self.map.raw_entry_mut(item) {
+        match self.map_mut().raw_entry_mut(item) {
             RawEntry::Occupied(mut e) => {
                 let info = e.get_mut();
                 info.count += 1;
@@ -72,7 +109,7 @@ impl<T: IsEnabled + Eq + Hash + Clone> DistanceCountMap<T> {
     }
 
     pub fn remove_clonable(&mut self, item: &T) -> bool {
-        match self.map.raw_entry_mut(item) {
+        match self.map_mut().raw_entry_mut(item) {
             RawEntry::Occupied(mut e) => {
                 let info = e.get_mut();
                 info.count -= 1;
@@ -97,11 +134,14 @@ impl<T: IsEnabled + Eq + Hash + Clone> DistanceCountMap<T> {
     }
 
     pub fn into_counts(self) -> impl Iterator<Item = (T, isize)> {
-        self.map.into_iter().map(|(item, info)| (item, info.count))
+        self.map
+            .into_iter()
+            .flat_map(|map| Arc::unwrap_or_clone(map).into_iter())
+            .map(|(item, info)| (item, info.count))
     }
 
     pub fn len(&self) -> usize {
-        self.map.len()
+        self.map.as_ref().map_or(0, |map| map.len())
     }
 }
 
@@ -129,12 +169,7 @@ impl<T, I: IsEnabled> BottomConnection<T, I> {
     pub fn as_cloned_uppers(&self) -> BottomUppers<T, I> {
         match self {
             Self::Left(upper) => BottomUppers::Left(upper.clone()),
-            Self::Inner(upper) => BottomUppers::Inner(
-                upper
-                    .iter()
-                    .map(|(item, distance)| (item.clone(), distance))
-                    .collect(),
-            ),
+            Self::Inner(upper) => BottomUppers::Inner(upper.clone()),
         }
     }
 
@@ -160,6 +195,20 @@ impl<T, I: IsEnabled> BottomConnection<T, I> {
 }
 
 impl<T, I: IsEnabled + Eq + Hash + Clone> BottomConnection<T, I> {
+    /// Returns the upper if there is exactly one.
+    pub fn single_upper(&self) -> Option<&Arc<BottomTree<T, I>>> {
+        match self {
+            BottomConnection::Left(upper) => Some(upper),
+            BottomConnection::Inner(list) => {
+                let mut iter = list.iter();
+                match (iter.next(), iter.next()) {
+                    (Some((BottomRef { upper }, _)), None) => Some(upper),
+                    _ => None,
+                }
+            }
+        }
+    }
+
     pub fn child_change<C: AggregationContext<Info = T, ItemRef = I>>(
         &self,
         aggregation_context: &C,
@@ -208,7 +257,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomConnection<T, I> {
 
 pub enum BottomUppers<T, I: IsEnabled> {
     Left(Arc<BottomTree<T, I>>),
-    Inner(StackVec<(BottomRef<T, I>, u8)>),
+    Inner(DistanceCountMap<BottomRef<T, I>>),
 }
 
 impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
@@ -224,7 +273,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
                 upper.add_children_of_child(aggregation_context, ChildLocation::Left, children, 0);
             }
             BottomUppers::Inner(list) => {
-                for &(BottomRef { ref upper }, nesting_level) in list {
+                for (BottomRef { upper }, nesting_level) in list.iter() {
                     upper.add_children_of_child(
                         aggregation_context,
                         ChildLocation::Inner,
@@ -251,7 +300,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
                 );
             }
             BottomUppers::Inner(list) => {
-                for &(BottomRef { ref upper }, nesting_level) in list.iter() {
+                for (BottomRef { upper }, nesting_level) in list.iter() {
                     upper.add_child_of_child(
                         aggregation_context,
                         ChildLocation::Inner,
@@ -263,6 +312,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
         }
     }
 
+    #[cfg(any(test, feature = "test-util"))]
     pub fn remove_child_of_child<C: AggregationContext<Info = T, ItemRef = I>>(
         &self,
         aggregation_context: &C,
@@ -273,7 +323,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
                 upper.remove_child_of_child(aggregation_context, child_of_child);
             }
             BottomUppers::Inner(list) => {
-                for (BottomRef { upper }, _) in list {
+                for (BottomRef { upper }, _) in list.iter() {
                     upper.remove_child_of_child(aggregation_context, child_of_child);
                 }
             }
@@ -292,7 +342,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
                 upper.remove_children_of_child(aggregation_context, children);
             }
             BottomUppers::Inner(list) => {
-                for (BottomRef { upper }, _) in list {
+                for (BottomRef { upper }, _) in list.iter() {
                     upper.remove_children_of_child(aggregation_context, children.clone());
                 }
             }
@@ -309,7 +359,7 @@ impl<T, I: IsEnabled + Eq + Hash + Clone> BottomUppers<T, I> {
                 upper.child_change(aggregation_context, change);
             }
             BottomUppers::Inner(list) => {
-                for (BottomRef { upper }, _) in list {
+                for (BottomRef { upper }, _) in list.iter() {
                     upper.child_change(aggregation_context, change);
                 }
             }
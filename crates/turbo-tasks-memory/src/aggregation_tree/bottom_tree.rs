@@ -1,4 +1,11 @@
-use std::{hash::Hash, ops::ControlFlow, sync::Arc};
+use std::{
+    hash::Hash,
+    ops::ControlFlow,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 use nohash_hasher::{BuildNoHashHasher, IsEnabled};
 use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
@@ -6,11 +13,15 @@ use ref_cast::RefCast;
 
 use super::{
     bottom_connection::BottomConnection,
+    contention,
     inner_refs::{BottomRef, ChildLocation, TopRef},
     leaf::{
         add_inner_upper_to_item, bottom_tree, remove_inner_upper_from_item,
         remove_left_upper_from_item,
     },
+    locked_node::{AggregatedNodeId, LockedNode},
+    propagation_trace,
+    reentrancy::{self, PropagationGuard},
     top_tree::TopTree,
     AggregationContext, StackVec, CHILDREN_INNER_THRESHOLD, CONNECTIVITY_LIMIT,
 };
@@ -22,32 +33,87 @@ use crate::count_hash_set::{CountHashSet, RemoveIfEntryResult};
 pub struct BottomTree<T, I: IsEnabled> {
     height: u8,
     item: I,
+    /// The number of items which have an entry for this bottom tree in their
+    /// uppers, i. e. which are connected to it as inner child. Each of them
+    /// holds a reference to it.
+    inner_items: AtomicUsize,
     state: RwLock<BottomTreeState<T, I>>,
 }
 
 pub struct BottomTreeState<T, I: IsEnabled> {
     data: T,
+    /// Incremented on every change applied to `data`.
+    generation: u64,
     bottom_upper: BottomConnection<T, I>,
-    top_upper: CountHashSet<TopRef<T>, BuildNoHashHasher<TopRef<T>>>,
+    /// Copy-on-write, so a change can capture the uppers cheaply.
+    top_upper: Arc<CountHashSet<TopRef<T>, BuildNoHashHasher<TopRef<T>>>>,
     // TODO can this become negative?
     following: CountHashSet<I, BuildNoHashHasher<I>>,
 }
 
+type WriteLockedState<'c, 'a, C> = LockedNode<
+    'c,
+    C,
+    RwLockWriteGuard<
+        'a,
+        BottomTreeState<<C as AggregationContext>::Info, <C as AggregationContext>::ItemRef>,
+    >,
+>;
+
 impl<T: Default, I: IsEnabled> BottomTree<T, I> {
     pub fn new(item: I, height: u8) -> Self {
         Self {
             height,
             item,
+            inner_items: AtomicUsize::new(0),
             state: RwLock::new(BottomTreeState {
                 data: T::default(),
+                generation: 0,
                 bottom_upper: BottomConnection::new(),
-                top_upper: CountHashSet::new(),
+                top_upper: Arc::new(CountHashSet::new()),
                 following: CountHashSet::new(),
             }),
         }
     }
 }
 
+impl<T, I: IsEnabled> BottomTree<T, I> {
+    /// Returns the height of the bottom tree. A bottom tree of height 0
+    /// aggregates its item and the item's children, every higher one
+    /// aggregates bottom trees of the height below.
+    pub fn height(&self) -> u8 {
+        self.height
+    }
+
+    /// Locks the state for writing and reports it to the context.
+    fn write<'c, C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &'c C,
+    ) -> WriteLockedState<'c, '_, C> {
+        reentrancy::assert_not_applying_change();
+        let state = self.state.write();
+        LockedNode::new(
+            aggregation_context,
+            AggregatedNodeId::of(&state.data),
+            state,
+        )
+    }
+
+    /// Locks the state for reading and reports it to the context.
+    fn read<'c, C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &'c C,
+    ) -> LockedNode<'c, C, RwLockReadGuard<'_, BottomTreeState<T, I>>> {
+        reentrancy::assert_not_applying_change();
+        let state = self.state.read();
+        LockedNode::new(
+            aggregation_context,
+            AggregatedNodeId::of(&state.data),
+            state,
+        )
+    }
+}
+
 impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
     pub fn add_children_of_child<'a, C: AggregationContext<Info = T, ItemRef = I>>(
         self: &Arc<Self>,
@@ -75,14 +141,18 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
                     return;
                 }
 
-                self.add_children_of_child_if_following(&mut children);
+                self.add_children_of_child_if_following(aggregation_context, &mut children);
                 self.add_children_of_child_inner(aggregation_context, children, nesting_level);
             }
         }
     }
 
-    fn add_children_of_child_if_following(&self, children: &mut StackVec<&I>) {
-        let mut state = self.state.write();
+    fn add_children_of_child_if_following<C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &C,
+        children: &mut StackVec<&I>,
+    ) {
+        let mut state = self.write(aggregation_context);
         children.retain(|&mut child| !state.following.add_if_entry(child));
     }
 
@@ -91,15 +161,15 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         mut children: StackVec<&I>,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         children.retain(|&mut child| state.following.add_clonable(child));
         if children.is_empty() {
             return;
         }
         let buttom_uppers = state.bottom_upper.as_cloned_uppers();
-        let top_upper = state.top_upper.iter().cloned().collect::<Vec<_>>();
+        let top_upper = state.top_upper.clone();
         drop(state);
-        for TopRef { upper } in top_upper {
+        for TopRef { upper } in top_upper.iter() {
             upper.add_children_of_child(aggregation_context, children.iter().copied());
         }
         buttom_uppers.add_children_of_child(aggregation_context, children.iter().copied());
@@ -157,7 +227,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
                     // but it's not a blue node and we are not too deep
                     // this means it's a inner child of this node
                     // if it's not already a following child
-                    if !self.add_child_of_child_if_following(child_of_child) {
+                    if !self.add_child_of_child_if_following(aggregation_context, child_of_child) {
                         self.add_child_of_child_inner(
                             aggregation_context,
                             child_of_child,
@@ -174,8 +244,12 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         }
     }
 
-    fn add_child_of_child_if_following(&self, child_of_child: &I) -> bool {
-        let mut state = self.state.write();
+    fn add_child_of_child_if_following<C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &C,
+        child_of_child: &I,
+    ) -> bool {
+        let mut state = self.write(aggregation_context);
         state.following.add_if_entry(child_of_child)
     }
 
@@ -184,7 +258,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         child_of_child: &I,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         if !state.following.add_clonable(child_of_child) {
             // Already connect, nothing more to do
             return;
@@ -237,7 +311,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         child_of_child: &I,
     ) -> bool {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         match state.following.remove_if_entry(child_of_child) {
             RemoveIfEntryResult::PartiallyRemoved => return true,
             RemoveIfEntryResult::NotPresent => return false,
@@ -252,7 +326,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         children: &mut Vec<&'a I>,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         let mut removed = StackVec::default();
         children.retain(|&child| match state.following.remove_if_entry(child) {
             RemoveIfEntryResult::PartiallyRemoved => false,
@@ -272,7 +346,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         child_of_child: &I,
     ) -> bool {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         if !state.following.remove_clonable(child_of_child) {
             // no present, nothing to do
             return false;
@@ -286,7 +360,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         mut children: StackVec<&I>,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         children.retain(|&mut child| state.following.remove_clonable(child));
         propagate_lost_followings_to_uppers(state, aggregation_context, children);
     }
@@ -338,7 +412,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         upper: &Arc<BottomTree<T, I>>,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         let old_inner = state.bottom_upper.set_left_upper(upper);
         let add_change = aggregation_context.info_to_add_change(&state.data);
         let children = state.following.iter().cloned().collect::<StackVec<_>>();
@@ -397,7 +471,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         remove_change: &Option<C::ItemChange>,
         following: &[I],
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         if count > 0 {
             // add as following
             if state.following.add_count(item.clone(), count as usize) {
@@ -425,7 +499,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         upper: &Arc<BottomTree<T, I>>,
         nesting_level: u8,
     ) -> bool {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         let number_of_following = state.following.len();
         let BottomConnection::Inner(inner) = &mut state.bottom_upper else {
             return false;
@@ -457,7 +531,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         upper: &Arc<BottomTree<T, I>>,
     ) {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         state.bottom_upper.unset_left_upper(upper);
         if let Some(change) = aggregation_context.info_to_remove_change(&state.data) {
             upper.child_change(aggregation_context, &change);
@@ -478,7 +552,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         upper: &Arc<BottomTree<T, I>>,
     ) -> bool {
-        let mut state = self.state.write();
+        let mut state = self.write(aggregation_context);
         let BottomConnection::Inner(inner) = &mut state.bottom_upper else {
             return false;
         };
@@ -500,8 +574,8 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         upper: &Arc<TopTree<T>>,
     ) {
-        let mut state = self.state.write();
-        let new = state.top_upper.add_clonable(TopRef::ref_cast(upper));
+        let mut state = self.write(aggregation_context);
+        let new = Arc::make_mut(&mut state.top_upper).add_clonable(TopRef::ref_cast(upper));
         if new {
             if let Some(change) = aggregation_context.info_to_add_change(&state.data) {
                 upper.child_change(aggregation_context, &change);
@@ -518,8 +592,8 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         aggregation_context: &C,
         upper: &Arc<TopTree<T>>,
     ) {
-        let mut state = self.state.write();
-        let removed = state.top_upper.remove_clonable(TopRef::ref_cast(upper));
+        let mut state = self.write(aggregation_context);
+        let removed = Arc::make_mut(&mut state.top_upper).remove_clonable(TopRef::ref_cast(upper));
         if removed {
             if let Some(change) = aggregation_context.info_to_remove_change(&state.data) {
                 upper.child_change(aggregation_context, &change);
@@ -548,15 +622,61 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
         }
     }
 
+    /// Updates the number of inner items after the entry of this bottom tree in
+    /// the uppers of an item has been modified. `entries_before` and
+    /// `entries_after` are the number of entries in the uppers of the item.
+    pub fn update_inner_items(&self, entries_before: usize, entries_after: usize) {
+        match entries_after.cmp(&entries_before) {
+            std::cmp::Ordering::Greater => {
+                self.inner_items.fetch_add(1, Ordering::SeqCst);
+            }
+            std::cmp::Ordering::Less => {
+                self.inner_items.fetch_sub(1, Ordering::SeqCst);
+            }
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+
+    /// Returns true when the bottom tree isn't connected to any upper and can't
+    /// be connected to one concurrently. Uppers are only connected through a
+    /// reference to the bottom tree, so this is the case when the only
+    /// references are the `left_references` of the left child and those of the
+    /// inner items. It's decided under the lock of the bottom tree.
+    pub fn is_released<C: AggregationContext<Info = T, ItemRef = I>>(
+        self: &Arc<Self>,
+        aggregation_context: &C,
+        left_references: usize,
+    ) -> bool {
+        let state = self.read(aggregation_context);
+        state.bottom_upper.is_unset()
+            && state.top_upper.is_empty()
+            && Arc::strong_count(self) <= left_references + self.inner_items.load(Ordering::SeqCst)
+    }
+
     pub fn child_change<C: AggregationContext<Info = T, ItemRef = I>>(
         &self,
         aggregation_context: &C,
         change: &C::ItemChange,
     ) {
-        let mut state = self.state.write();
-        let change = aggregation_context.apply_change(&mut state.data, change);
-        let state = RwLockWriteGuard::downgrade(state);
-        propagate_change_to_upper(&state, aggregation_context, change);
+        let _guard = PropagationGuard::enter();
+        propagation_trace::visit(self, "bottom_tree", self.height);
+        let state = contention::lock_timed(|| self.state.write());
+        let mut state = LockedNode::new(
+            aggregation_context,
+            AggregatedNodeId::of(&state.data),
+            state,
+        );
+        let change = reentrancy::apply_change(aggregation_context, &mut state.data, change);
+        state.generation += 1;
+        propagate_change_to_upper(state, aggregation_context, change);
+    }
+
+    /// Calls `func` with the aggregated info, the number of following items
+    /// and the generation of the info.
+    pub fn with_info<R>(&self, func: impl FnOnce(&T, usize, u64) -> R) -> R {
+        reentrancy::assert_not_applying_change();
+        let state = self.state.read();
+        func(&state.data, state.following.len(), state.generation)
     }
 
     pub fn get_root_info<C: AggregationContext<Info = T, ItemRef = I>>(
@@ -566,8 +686,8 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
     ) -> C::RootInfo {
         let mut result = aggregation_context.new_root_info(root_info_type);
         let top_uppers = {
-            let state = self.state.read();
-            state.top_upper.iter().cloned().collect::<StackVec<_>>()
+            let state = self.read(aggregation_context);
+            state.top_upper.clone()
         };
         for TopRef { upper } in top_uppers.iter() {
             let info = upper.get_root_info(aggregation_context, root_info_type);
@@ -576,7 +696,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
             }
         }
         let bottom_uppers = {
-            let state = self.state.read();
+            let state = self.read(aggregation_context);
             state.bottom_upper.as_cloned_uppers()
         };
         bottom_uppers.get_root_info(aggregation_context, root_info_type, result)
@@ -584,57 +704,61 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> BottomTree<T, I> {
 }
 
 fn propagate_lost_following_to_uppers<C: AggregationContext>(
-    state: RwLockWriteGuard<'_, BottomTreeState<C::Info, C::ItemRef>>,
+    state: WriteLockedState<'_, '_, C>,
     aggregation_context: &C,
     child_of_child: &C::ItemRef,
 ) {
     let bottom_uppers = state.bottom_upper.as_cloned_uppers();
-    let top_upper = state.top_upper.iter().cloned().collect::<StackVec<_>>();
+    let top_upper = state.top_upper.clone();
     drop(state);
-    for TopRef { upper } in top_upper {
+    for TopRef { upper } in top_upper.iter() {
         upper.remove_child_of_child(aggregation_context, child_of_child);
     }
     bottom_uppers.remove_child_of_child(aggregation_context, child_of_child);
 }
 
 fn propagate_lost_followings_to_uppers<'a, C: AggregationContext>(
-    state: RwLockWriteGuard<'_, BottomTreeState<C::Info, C::ItemRef>>,
+    state: WriteLockedState<'_, '_, C>,
     aggregation_context: &C,
     children: impl IntoIterator<Item = &'a C::ItemRef> + Clone,
 ) where
     C::ItemRef: 'a,
 {
     let bottom_uppers = state.bottom_upper.as_cloned_uppers();
-    let top_upper = state.top_upper.iter().cloned().collect::<Vec<_>>();
+    let top_upper = state.top_upper.clone();
     drop(state);
-    for TopRef { upper } in top_upper {
+    for TopRef { upper } in top_upper.iter() {
         upper.remove_children_of_child(aggregation_context, children.clone());
     }
     bottom_uppers.remove_children_of_child(aggregation_context, children);
 }
 
 fn propagate_new_following_to_uppers<C: AggregationContext>(
-    state: RwLockWriteGuard<'_, BottomTreeState<C::Info, C::ItemRef>>,
+    state: WriteLockedState<'_, '_, C>,
     aggregation_context: &C,
     child_of_child: &C::ItemRef,
 ) {
     let bottom_uppers = state.bottom_upper.as_cloned_uppers();
-    let top_upper = state.top_upper.iter().cloned().collect::<Vec<_>>();
+    let top_upper = state.top_upper.clone();
     drop(state);
-    for TopRef { upper } in top_upper {
+    for TopRef { upper } in top_upper.iter() {
         upper.add_child_of_child(aggregation_context, child_of_child);
     }
     bottom_uppers.add_child_of_child(aggregation_context, child_of_child);
 }
 
+/// Propagates a change to the uppers. The lock is downgraded and held until
+/// the change has been propagated, so the next change to this tree waits for
+/// it and changes arrive at the uppers in the order they have been applied.
 fn propagate_change_to_upper<C: AggregationContext>(
-    state: &RwLockReadGuard<BottomTreeState<C::Info, C::ItemRef>>,
+    state: WriteLockedState<'_, '_, C>,
     aggregation_context: &C,
     change: Option<C::ItemChange>,
 ) {
     let Some(change) = change else {
         return;
     };
+    let state = state.downgrade();
     state
         .bottom_upper
         .child_change(aggregation_context, &change);
@@ -0,0 +1,213 @@
+use std::hash::Hash;
+
+use auto_hash_map::{map::Entry, AutoMap};
+use smallvec::SmallVec;
+
+use super::AggregatedData;
+
+/// Counts per key, like the dirty tasks of a subgraph, with a bounded number
+/// of entries. Once more keys than the limit passed to [Self::apply_change]
+/// would be tracked, it switches to a summary which only tracks the total
+/// count. An [super::AggregationContext] passes its
+/// [super::AggregationContext::detail_limit] as limit through
+/// [AggregatedData::apply_change_with_limit].
+///
+/// The details of a summary are lost, but changes are still forwarded to upper
+/// aggregated nodes as they are received. So an upper keeps its details as
+/// long as it stays within its own limit. Only adding a summary to an upper or
+/// removing it sends a [BoundedCountChange::Total], which summarizes the upper
+/// as well. The details are recovered when the summary switches back, or when
+/// the aggregated node is removed from an upper and added again, which sends
+/// the current info.
+///
+/// It switches back once the total count is zero again, but only when all
+/// counts are known to be non-negative. Otherwise a negative count of one key
+/// might balance out a positive count of another one and the details tracked
+/// after switching back would be wrong. Changes can be applied out of order, so
+/// a count can be negative temporarily. A summary knows about a negative count
+/// when one has been summarized or when the total has been negative. In that
+/// case it's kept until it's removed from the aggregated node.
+pub enum BoundedCountMap<K> {
+    Detailed(AutoMap<K, i32>),
+    Summary { total: i32, maybe_negative: bool },
+}
+
+/// A change of a [BoundedCountMap].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BoundedCountChange<K> {
+    /// Updates the counts of some keys.
+    Entries(SmallVec<[(K, i32); 1]>),
+    /// Updates the total count only. This is used when the details are not
+    /// known, because the changed info is summarized.
+    Total(i32),
+}
+
+impl<K> Default for BoundedCountMap<K> {
+    fn default() -> Self {
+        Self::Detailed(AutoMap::new())
+    }
+}
+
+impl<K: Hash + Eq + Clone> BoundedCountMap<K> {
+    /// Returns true when the map only tracks the total count.
+    pub fn is_summary(&self) -> bool {
+        matches!(self, Self::Summary { .. })
+    }
+
+    /// Returns the total count.
+    pub fn total(&self) -> i32 {
+        match self {
+            Self::Detailed(map) => map.values().sum(),
+            Self::Summary { total, .. } => *total,
+        }
+    }
+
+    /// Returns the count of a key, or None when the map is summarized.
+    pub fn get(&self, key: &K) -> Option<i32> {
+        match self {
+            Self::Detailed(map) => Some(map.get(key).copied().unwrap_or_default()),
+            Self::Summary { .. } => None,
+        }
+    }
+
+    /// Applies a change, keeping at most `limit` keys. Returns the change that
+    /// should be applied to the next aggregation level.
+    pub fn apply_change(
+        &mut self,
+        limit: usize,
+        change: &BoundedCountChange<K>,
+    ) -> Option<BoundedCountChange<K>> {
+        match (&mut *self, change) {
+            (Self::Detailed(map), BoundedCountChange::Entries(entries)) => {
+                for (key, count) in entries {
+                    match map.entry(key.clone()) {
+                        Entry::Occupied(mut e) => {
+                            let value = e.get_mut();
+                            *value += count;
+                            if *value == 0 {
+                                e.remove();
+                            }
+                        }
+                        Entry::Vacant(e) => {
+                            if *count != 0 {
+                                e.insert(*count);
+                            }
+                        }
+                    }
+                }
+                if map.len() > limit {
+                    *self = Self::summarize(map, 0);
+                }
+            }
+            (Self::Detailed(map), &BoundedCountChange::Total(count)) => {
+                *self = Self::summarize(map, count);
+            }
+            (
+                Self::Summary {
+                    total,
+                    maybe_negative,
+                },
+                BoundedCountChange::Entries(entries),
+            ) => {
+                for (_, count) in entries {
+                    *total += count;
+                    *maybe_negative |= *total < 0;
+                }
+            }
+            (
+                Self::Summary {
+                    total,
+                    maybe_negative,
+                },
+                BoundedCountChange::Total(count),
+            ) => {
+                *total += count;
+                *maybe_negative |= *total < 0;
+            }
+        }
+        if let Self::Summary {
+            total: 0,
+            maybe_negative: false,
+        } = self
+        {
+            *self = Self::default();
+        }
+        match change {
+            BoundedCountChange::Entries(entries) if entries.is_empty() => None,
+            BoundedCountChange::Total(0) => None,
+            change => Some(change.clone()),
+        }
+    }
+
+    fn summarize(map: &AutoMap<K, i32>, count: i32) -> Self {
+        let total = map.values().sum::<i32>() + count;
+        Self::Summary {
+            total,
+            maybe_negative: total < 0 || map.values().any(|&count| count < 0),
+        }
+    }
+
+    /// Creates a change that represents adding this info to an upper.
+    pub fn to_add_change(&self) -> Option<BoundedCountChange<K>> {
+        self.to_change(1)
+    }
+
+    /// Creates a change that represents removing this info from an upper.
+    pub fn to_remove_change(&self) -> Option<BoundedCountChange<K>> {
+        self.to_change(-1)
+    }
+
+    fn to_change(&self, sign: i32) -> Option<BoundedCountChange<K>> {
+        match self {
+            Self::Detailed(map) if map.is_empty() => None,
+            Self::Detailed(map) => Some(BoundedCountChange::Entries(
+                map.iter()
+                    .map(|(key, count)| (key.clone(), count * sign))
+                    .collect(),
+            )),
+            Self::Summary { total, .. } => Some(BoundedCountChange::Total(total * sign)),
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone> AggregatedData for BoundedCountMap<K> {
+    type Change = BoundedCountChange<K>;
+
+    fn merge_change(change: &mut Self::Change, other: Self::Change) {
+        match (&mut *change, other) {
+            (BoundedCountChange::Entries(entries), BoundedCountChange::Entries(other)) => {
+                entries.extend(other);
+            }
+            (BoundedCountChange::Total(total), BoundedCountChange::Total(other)) => {
+                *total += other;
+            }
+            (BoundedCountChange::Entries(entries), BoundedCountChange::Total(other)) => {
+                let total = entries.iter().map(|(_, count)| count).sum::<i32>() + other;
+                *change = BoundedCountChange::Total(total);
+            }
+            (BoundedCountChange::Total(total), BoundedCountChange::Entries(other)) => {
+                *total += other.iter().map(|(_, count)| count).sum::<i32>();
+            }
+        }
+    }
+
+    fn apply_change(&mut self, change: &Self::Change) -> Option<Self::Change> {
+        self.apply_change_with_limit(usize::MAX, change)
+    }
+
+    fn apply_change_with_limit(
+        &mut self,
+        detail_limit: usize,
+        change: &Self::Change,
+    ) -> Option<Self::Change> {
+        BoundedCountMap::apply_change(self, detail_limit, change)
+    }
+
+    fn to_add_change(&self) -> Option<Self::Change> {
+        BoundedCountMap::to_add_change(self)
+    }
+
+    fn to_remove_change(&self) -> Option<Self::Change> {
+        BoundedCountMap::to_remove_change(self)
+    }
+}
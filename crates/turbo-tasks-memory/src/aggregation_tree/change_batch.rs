@@ -0,0 +1,73 @@
+use auto_hash_map::{map::Entry, AutoMap};
+use nohash_hasher::BuildNoHashHasher;
+use smallvec::SmallVec;
+
+use super::{leaf::PreparedChange, AggregationContext, AggregationTreeLeaf};
+
+type Prepared<C> = PreparedChange<
+    <C as AggregationContext>::Info,
+    <C as AggregationContext>::ItemRef,
+    <C as AggregationContext>::ItemChange,
+>;
+
+/// Accumulates changes of multiple leafs and applies them at once. Every change
+/// is prepared when it's added, so it's applied to the uppers the leaf had at
+/// that time, like a [PreparedChange]. Changes for the same uppers are merged
+/// with [AggregationContext::merge_change], so they only cause a single
+/// propagation through the aggregation tree.
+pub struct ChangeBatch<C: AggregationContext> {
+    changes: AutoMap<usize, SmallVec<[Prepared<C>; 1]>, BuildNoHashHasher<usize>>,
+}
+
+impl<C: AggregationContext> Default for ChangeBatch<C> {
+    fn default() -> Self {
+        Self {
+            changes: AutoMap::with_hasher(),
+        }
+    }
+}
+
+impl<C: AggregationContext> ChangeBatch<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a change of a leaf to the batch. The leaf need to be locked, since
+    /// this captures its current uppers.
+    pub fn add(
+        &mut self,
+        aggregation_context: &C,
+        leaf: &AggregationTreeLeaf<C::Info, C::ItemRef>,
+        change: C::ItemChange,
+    ) {
+        let prepared = leaf.prepare_change(aggregation_context, change);
+        self.add_prepared(aggregation_context, prepared);
+    }
+
+    /// Adds a change that has already been prepared, e. g. under a lock that
+    /// is no longer held.
+    pub fn add_prepared(&mut self, aggregation_context: &C, prepared: Prepared<C>) {
+        match self.changes.entry(prepared.uppers_key()) {
+            Entry::Occupied(mut e) => {
+                let changes = e.get_mut();
+                let last = changes.last_mut().unwrap();
+                if let Some(prepared) = last.merge(aggregation_context, prepared) {
+                    changes.push(prepared);
+                }
+            }
+            Entry::Vacant(e) => {
+                e.insert(SmallVec::from_buf([prepared]));
+            }
+        }
+    }
+
+    /// Applies all changes in the batch. This should be called after all leafs
+    /// have been unlocked.
+    pub fn flush(self, aggregation_context: &C) {
+        for (_, changes) in self.changes {
+            for prepared in changes {
+                prepared.apply(aggregation_context);
+            }
+        }
+    }
+}
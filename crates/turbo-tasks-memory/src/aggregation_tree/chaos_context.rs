@@ -0,0 +1,161 @@
+use std::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use super::{promotion::PromotionThresholds, AggregatedNodeId, AggregationContext};
+
+/// A wrapper around an [AggregationContext] which injects randomized delays
+/// and yield points before items are locked and while changes are applied to
+/// aggregated nodes. This widens the windows between capturing the uppers of
+/// an item under its lock and applying the change to them, so tests can shake
+/// out ordering assumptions.
+///
+/// The decisions are derived from a seed, so a single threaded run is
+/// reproducible. With multiple threads the sequence of decisions is the same,
+/// but the assignment to threads depends on scheduling.
+pub struct ChaosContext<C: AggregationContext> {
+    inner: C,
+    seed: u64,
+    counter: AtomicU64,
+    max_delay: Duration,
+}
+
+impl<C: AggregationContext> ChaosContext<C> {
+    pub fn new(inner: C, seed: u64, max_delay: Duration) -> Self {
+        Self {
+            inner,
+            seed,
+            counter: AtomicU64::new(0),
+            max_delay,
+        }
+    }
+
+    /// Returns the inner context.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    fn next_random(&self) -> u64 {
+        // SplitMix64
+        let n = self.counter.fetch_add(1, Ordering::Relaxed);
+        let mut z = self
+            .seed
+            .wrapping_add(n.wrapping_add(1).wrapping_mul(0x9e3779b97f4a7c15));
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Wraps a job, e. g. one returned by
+    /// [super::AggregationTreeLeaf::change_job], so that a random delay is
+    /// injected between preparing the job and executing it.
+    pub fn wrap_job<'a>(&'a self, job: impl FnOnce() + 'a) -> impl FnOnce() + 'a {
+        move || {
+            self.chaos();
+            job();
+        }
+    }
+
+    fn chaos(&self) {
+        let random = self.next_random();
+        match random % 4 {
+            0 => {}
+            1 => thread::yield_now(),
+            2 => {
+                for _ in 0..(random >> 8) % 1000 {
+                    std::hint::spin_loop();
+                }
+            }
+            _ => {
+                let max_nanos = self.max_delay.as_nanos() as u64;
+                if max_nanos > 0 {
+                    thread::sleep(Duration::from_nanos((random >> 8) % max_nanos));
+                }
+            }
+        }
+    }
+}
+
+impl<C: AggregationContext> AggregationContext for ChaosContext<C> {
+    type ItemLock<'a> = C::ItemLock<'a> where Self: 'a;
+    type Info = C::Info;
+    type ItemChange = C::ItemChange;
+    type ItemRef = C::ItemRef;
+    type RootInfo = C::RootInfo;
+    type RootInfoType = C::RootInfoType;
+
+    fn item<'a>(&'a self, reference: &Self::ItemRef) -> Self::ItemLock<'a> {
+        self.chaos();
+        self.inner.item(reference)
+    }
+
+    fn apply_change(
+        &self,
+        info: &mut Self::Info,
+        change: &Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        self.chaos();
+        self.inner.apply_change(info, change)
+    }
+
+    fn merge_change(
+        &self,
+        change: &mut Self::ItemChange,
+        other: Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        self.inner.merge_change(change, other)
+    }
+
+    fn info_to_add_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.chaos();
+        self.inner.info_to_add_change(info)
+    }
+
+    fn info_to_remove_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.chaos();
+        self.inner.info_to_remove_change(info)
+    }
+
+    fn new_root_info(&self, root_info_type: &Self::RootInfoType) -> Self::RootInfo {
+        self.inner.new_root_info(root_info_type)
+    }
+
+    fn info_to_root_info(
+        &self,
+        info: &Self::Info,
+        root_info_type: &Self::RootInfoType,
+    ) -> Self::RootInfo {
+        self.inner.info_to_root_info(info, root_info_type)
+    }
+
+    fn merge_root_info(
+        &self,
+        root_info: &mut Self::RootInfo,
+        other: Self::RootInfo,
+    ) -> ControlFlow<()> {
+        self.inner.merge_root_info(root_info, other)
+    }
+
+    fn detail_limit(&self) -> usize {
+        self.inner.detail_limit()
+    }
+
+    fn promotion_thresholds(&self) -> Option<PromotionThresholds> {
+        self.inner.promotion_thresholds()
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn on_lock_aggregated_node(&self, node: AggregatedNodeId) {
+        self.inner.on_lock_aggregated_node(node)
+    }
+
+    fn on_unlock_aggregated_node(&self, node: AggregatedNodeId) {
+        self.inner.on_unlock_aggregated_node(node)
+    }
+}
@@ -0,0 +1,105 @@
+/// Aggregated info that knows how to apply changes to itself. An
+/// [super::AggregationContext] can delegate `apply_change`,
+/// `info_to_add_change` and `info_to_remove_change` to it.
+///
+/// It's implemented for tuples, so a single aggregation tree can update
+/// multiple independent statistics (e. g. `(DirtyCount, ScheduledCount)`) in
+/// one propagation instead of maintaining one aggregation tree per statistic.
+/// The change of a tuple is a tuple of optional changes, where `None` means the
+/// statistic is unchanged.
+pub trait AggregatedData: Default {
+    type Change;
+
+    /// Merges `other` into `change`, so both are applied at once.
+    fn merge_change(change: &mut Self::Change, other: Self::Change);
+
+    /// Applies a change. Returns the change that should be applied to the next
+    /// aggregation level, or None if there is nothing to propagate.
+    fn apply_change(&mut self, change: &Self::Change) -> Option<Self::Change>;
+
+    /// Like [Self::apply_change], but keeps at most `detail_limit` details,
+    /// see [super::AggregationContext::detail_limit]. Info that doesn't bound
+    /// its details ignores the limit.
+    fn apply_change_with_limit(
+        &mut self,
+        detail_limit: usize,
+        change: &Self::Change,
+    ) -> Option<Self::Change> {
+        let _ = detail_limit;
+        self.apply_change(change)
+    }
+
+    /// Creates a change that represents adding this info to an upper.
+    fn to_add_change(&self) -> Option<Self::Change>;
+
+    /// Creates a change that represents removing this info from an upper.
+    fn to_remove_change(&self) -> Option<Self::Change>;
+}
+
+macro_rules! impl_aggregated_data_for_tuple {
+    ($($name:ident $index:tt),+) => {
+        impl<$($name: AggregatedData),+> AggregatedData for ($($name,)+) {
+            type Change = ($(Option<$name::Change>,)+);
+
+            fn merge_change(change: &mut Self::Change, other: Self::Change) {
+                $(
+                    match (&mut change.$index, other.$index) {
+                        (_, None) => {}
+                        (None, other) => change.$index = other,
+                        (Some(change), Some(other)) => $name::merge_change(change, other),
+                    }
+                )+
+            }
+
+            fn apply_change(&mut self, change: &Self::Change) -> Option<Self::Change> {
+                let result = ($(
+                    change.$index.as_ref().and_then(|change| self.$index.apply_change(change)),
+                )+);
+                if $(result.$index.is_none())&&+ {
+                    None
+                } else {
+                    Some(result)
+                }
+            }
+
+            fn apply_change_with_limit(
+                &mut self,
+                detail_limit: usize,
+                change: &Self::Change,
+            ) -> Option<Self::Change> {
+                let result = ($(
+                    change.$index.as_ref().and_then(|change| {
+                        self.$index.apply_change_with_limit(detail_limit, change)
+                    }),
+                )+);
+                if $(result.$index.is_none())&&+ {
+                    None
+                } else {
+                    Some(result)
+                }
+            }
+
+            fn to_add_change(&self) -> Option<Self::Change> {
+                let result = ($(self.$index.to_add_change(),)+);
+                if $(result.$index.is_none())&&+ {
+                    None
+                } else {
+                    Some(result)
+                }
+            }
+
+            fn to_remove_change(&self) -> Option<Self::Change> {
+                let result = ($(self.$index.to_remove_change(),)+);
+                if $(result.$index.is_none())&&+ {
+                    None
+                } else {
+                    Some(result)
+                }
+            }
+        }
+    };
+}
+
+impl_aggregated_data_for_tuple!(A 0, B 1);
+impl_aggregated_data_for_tuple!(A 0, B 1, C 2);
+impl_aggregated_data_for_tuple!(A 0, B 1, C 2, D 3);
@@ -0,0 +1,105 @@
+//! Measures how long change propagation waits for the locks of aggregated
+//! nodes. This is only enabled with the `aggregation_contention` feature,
+//! otherwise all functions are no-ops.
+//!
+//! Wait times are collected per thread for every propagation wave, i. e. the
+//! outermost propagation of a change, and merged into global stats when the
+//! wave has finished.
+
+#[cfg(feature = "aggregation_contention")]
+mod enabled {
+    use std::{cell::RefCell, time::Duration};
+
+    use parking_lot::Mutex;
+
+    /// The number of buckets of the wait time histogram. Bucket `i` counts
+    /// lock acquisitions which waited less than `2^i` microseconds, the last
+    /// bucket counts all longer waits.
+    pub const HISTOGRAM_BUCKETS: usize = 16;
+
+    /// Lock contention stats of aggregated nodes during change propagation.
+    #[derive(Debug, Default, Clone, PartialEq, Eq)]
+    pub struct ContentionStats {
+        /// The number of propagation waves.
+        pub waves: u64,
+        /// The number of lock acquisitions.
+        pub locks: u64,
+        /// The total time spent waiting for locks.
+        pub total_wait: Duration,
+        /// The longest total wait time of a single wave.
+        pub max_wave_wait: Duration,
+        /// Histogram of the wait time of single lock acquisitions.
+        pub histogram: [u64; HISTOGRAM_BUCKETS],
+    }
+
+    #[derive(Default)]
+    struct WaveStats {
+        locks: u64,
+        wait: Duration,
+        histogram: [u64; HISTOGRAM_BUCKETS],
+    }
+
+    thread_local! {
+        static WAVE: RefCell<WaveStats> = RefCell::new(WaveStats::default());
+    }
+
+    static STATS: Mutex<Option<ContentionStats>> = Mutex::new(None);
+
+    pub fn record(wait: Duration) {
+        let bucket = (u128::BITS - wait.as_micros().leading_zeros()) as usize;
+        WAVE.with(|wave| {
+            let mut wave = wave.borrow_mut();
+            wave.locks += 1;
+            wave.wait += wait;
+            wave.histogram[bucket.min(HISTOGRAM_BUCKETS - 1)] += 1;
+        });
+    }
+
+    pub fn finish_wave() {
+        let wave = WAVE.with(|wave| std::mem::take(&mut *wave.borrow_mut()));
+        let mut stats = STATS.lock();
+        let stats = stats.get_or_insert_with(Default::default);
+        stats.waves += 1;
+        stats.locks += wave.locks;
+        stats.total_wait += wave.wait;
+        stats.max_wave_wait = stats.max_wave_wait.max(wave.wait);
+        for (total, count) in stats.histogram.iter_mut().zip(wave.histogram) {
+            *total += count;
+        }
+    }
+
+    /// Returns the contention stats collected so far.
+    pub fn contention_stats() -> ContentionStats {
+        STATS.lock().clone().unwrap_or_default()
+    }
+
+    /// Returns the contention stats collected so far and resets them.
+    pub fn take_contention_stats() -> ContentionStats {
+        STATS.lock().take().unwrap_or_default()
+    }
+}
+
+#[cfg(feature = "aggregation_contention")]
+pub use enabled::{contention_stats, take_contention_stats, ContentionStats, HISTOGRAM_BUCKETS};
+
+/// Acquires a lock of an aggregated node and records the time waited for it.
+#[inline(always)]
+pub fn lock_timed<G>(lock: impl FnOnce() -> G) -> G {
+    #[cfg(feature = "aggregation_contention")]
+    {
+        let start = std::time::Instant::now();
+        let guard = lock();
+        enabled::record(start.elapsed());
+        guard
+    }
+    #[cfg(not(feature = "aggregation_contention"))]
+    lock()
+}
+
+/// Called when the outermost propagation of a change on the current thread has
+/// finished.
+#[inline(always)]
+pub fn finish_wave() {
+    #[cfg(feature = "aggregation_contention")]
+    enabled::finish_wave();
+}
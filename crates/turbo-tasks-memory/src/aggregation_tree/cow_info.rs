@@ -0,0 +1,42 @@
+use std::{ops::Deref, sync::Arc};
+
+/// Aggregated info stored behind an [Arc] with copy-on-write semantics. An
+/// [super::AggregationContext] can use this as `Info` type to make snapshots
+/// of aggregated info cheap: Taking a snapshot by cloning it only clones the
+/// [Arc], while [CowInfo::make_mut] only deep clones the info when a snapshot
+/// of it is still alive.
+pub struct CowInfo<T> {
+    inner: Arc<T>,
+}
+
+impl<T: Default> Default for CowInfo<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(T::default()),
+        }
+    }
+}
+
+impl<T> Clone for CowInfo<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Deref for CowInfo<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: Clone> CowInfo<T> {
+    /// Gets mutable access to the info. Clones the info first when it's shared
+    /// with a snapshot.
+    pub fn make_mut(&mut self) -> &mut T {
+        Arc::make_mut(&mut self.inner)
+    }
+}
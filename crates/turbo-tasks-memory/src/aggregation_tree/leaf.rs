@@ -1,18 +1,40 @@
-use std::{hash::Hash, sync::Arc};
+use std::{
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, OnceLock,
+    },
+};
 
 use auto_hash_map::AutoSet;
 use nohash_hasher::IsEnabled;
 use ref_cast::RefCast;
+use smallvec::SmallVec;
 use tracing::Level;
 
 use super::{
-    bottom_connection::{BottomConnection, DistanceCountMap},
+    barrier::{PendingChange, PendingChanges},
+    bottom_connection::{BottomConnection, BottomUppers, DistanceCountMap},
     bottom_tree::BottomTree,
     inner_refs::{BottomRef, ChildLocation},
+    promotion::ChangeFrequency,
+    reentrancy,
     top_tree::TopTree,
-    AggregationContext, AggregationItemLock, LargeStackVec, CHILDREN_INNER_THRESHOLD,
+    AggregationContext, AggregationInfoReference, AggregationItemLock, LargeStackVec,
+    CHILDREN_INNER_THRESHOLD,
 };
 
+/// How a leaf is connected to the upper bottom trees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpperKind {
+    /// The leaf is the left-most child of a single bottom tree, which
+    /// aggregates the leaf and its children.
+    Left,
+    /// The leaf is an inner child of zero or more bottom trees.
+    Inner,
+}
+
 /// The leaf of the aggregation tree. It's usually stored inside of the nodes
 /// that should be aggregated by the aggregation tree. It caches [TopTree]s and
 /// [BottomTree]s created from that node. And it also stores the upper bottom
@@ -21,6 +43,15 @@ pub struct AggregationTreeLeaf<T, I: IsEnabled> {
     top_trees: Vec<Option<Arc<TopTree<T>>>>,
     bottom_trees: Vec<Option<Arc<BottomTree<T, I>>>>,
     upper: BottomConnection<T, I>,
+    change_frequency: ChangeFrequency,
+    in_flight: InFlightChanges,
+    pending: PendingChanges,
+}
+
+impl<T, I: Clone + Eq + Hash + IsEnabled> Default for AggregationTreeLeaf<T, I> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
@@ -29,6 +60,9 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
             top_trees: Vec::new(),
             bottom_trees: Vec::new(),
             upper: BottomConnection::new(),
+            change_frequency: ChangeFrequency::default(),
+            in_flight: InFlightChanges::default(),
+            pending: PendingChanges::default(),
         }
     }
 
@@ -45,7 +79,9 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
         T: 'a,
     {
         let uppers = self.upper.as_cloned_uppers();
+        let in_flight = self.start_change();
         move || {
+            let _in_flight = in_flight;
             uppers.add_children_of_child(aggregation_context, &children);
         }
     }
@@ -61,12 +97,15 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
         T: 'a,
     {
         let uppers = self.upper.as_cloned_uppers();
+        let in_flight = self.start_change();
         move || {
+            let _in_flight = in_flight;
             uppers.add_child_of_child(aggregation_context, child);
         }
     }
 
     /// Removes a child.
+    #[cfg(any(test, feature = "test-util"))]
     pub fn remove_child<C: AggregationContext<Info = T, ItemRef = I>>(
         &self,
         aggregation_context: &C,
@@ -95,7 +134,40 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
         H: 'a,
     {
         let uppers = self.upper.as_cloned_uppers();
-        move || uppers.remove_children_of_child(aggregation_context, children.iter())
+        let in_flight = self.start_change();
+        move || {
+            let _in_flight = in_flight;
+            uppers.remove_children_of_child(aggregation_context, children.iter())
+        }
+    }
+
+    /// Prepares the removal of the contribution of the leaf to the aggregated
+    /// nodes in a single traversal. `remove_change` is usually the inverse of
+    /// the current info of the leaf and `children` are the children that are
+    /// removed. It returns a closure that should be executed outside of the
+    /// leaf lock.
+    pub fn remove_all_job<'a, C: AggregationContext<Info = T, ItemRef = I>, H, const N: usize>(
+        &self,
+        aggregation_context: &'a C,
+        remove_change: Option<C::ItemChange>,
+        children: AutoSet<I, H, N>,
+    ) -> impl FnOnce() + 'a
+    where
+        I: 'a,
+        T: 'a,
+        H: 'a,
+    {
+        let uppers = self.upper.as_cloned_uppers();
+        let in_flight = self.start_change();
+        move || {
+            let _in_flight = in_flight;
+            if let Some(change) = remove_change {
+                uppers.child_change(aggregation_context, &change);
+            }
+            if !children.is_empty() {
+                uppers.remove_children_of_child(aggregation_context, children.iter());
+            }
+        }
     }
 
     /// Communicates a change on the leaf to updated aggregated nodes. Prefer
@@ -105,6 +177,7 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
         aggregation_context: &C,
         change: &C::ItemChange,
     ) {
+        self.record_change(aggregation_context);
         self.upper.child_change(aggregation_context, change);
     }
 
@@ -120,12 +193,112 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
         I: 'a,
         T: 'a,
     {
-        let uppers = self.upper.as_cloned_uppers();
+        let prepared = self.prepare_change(aggregation_context, change);
+        move || {
+            prepared.apply(aggregation_context);
+        }
+    }
+
+    /// Prepares the communication of a change on the leaf to updated aggregated
+    /// nodes. Unlike [Self::change_job] the result doesn't borrow the
+    /// aggregation context, so it can be sent to another thread and applied
+    /// there. It should be applied outside of the leaf lock.
+    pub fn prepare_change<C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &C,
+        change: C::ItemChange,
+    ) -> PreparedChange<T, I, C::ItemChange> {
+        self.prepare_change_inner(aggregation_context, change, true)
+    }
+
+    /// Like [Self::change_job], but always captures the shared upper map
+    /// instead of a single upper. This allows to benchmark the fast path.
+    #[cfg(any(test, feature = "test-util"))]
+    pub fn change_job_without_fast_path<'a, C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &'a C,
+        change: C::ItemChange,
+    ) -> impl FnOnce() + 'a
+    where
+        I: 'a,
+        T: 'a,
+    {
+        let prepared = self.prepare_change_inner(aggregation_context, change, false);
         move || {
-            uppers.child_change(aggregation_context, &change);
+            prepared.apply(aggregation_context);
+        }
+    }
+
+    fn prepare_change_inner<C: AggregationContext<Info = T, ItemRef = I>>(
+        &self,
+        aggregation_context: &C,
+        change: C::ItemChange,
+        single_upper_fast_path: bool,
+    ) -> PreparedChange<T, I, C::ItemChange> {
+        self.record_change(aggregation_context);
+        let single_upper = if single_upper_fast_path {
+            self.upper.single_upper()
+        } else {
+            None
+        };
+        let uppers = match single_upper {
+            Some(upper) => PreparedUppers::Single(upper.clone()),
+            None => PreparedUppers::Multiple(self.upper.as_cloned_uppers()),
+        };
+        PreparedChange {
+            uppers,
+            change,
+            in_flight: SmallVec::from_buf([self.start_change()]),
+        }
+    }
+
+    /// Registers a change that is applied after the leaf lock has been
+    /// released, until the returned guard is dropped.
+    fn start_change(&self) -> InFlightChange {
+        let count = self.in_flight.0.get_or_init(Default::default).clone();
+        count.fetch_add(1, Ordering::Relaxed);
+        InFlightChange {
+            count,
+            _pending: PendingChange::new(&self.pending),
+        }
+    }
+
+    fn record_change<C: AggregationContext>(&self, aggregation_context: &C) {
+        if let Some(thresholds) = aggregation_context.promotion_thresholds() {
+            self.change_frequency
+                .record(aggregation_context.now(), thresholds.half_life);
+        }
+    }
+
+    fn record_promotion<C: AggregationContext>(&mut self, aggregation_context: &C) {
+        if aggregation_context.promotion_thresholds().is_some() {
+            self.change_frequency
+                .record_promotion(aggregation_context.now());
+        }
+    }
+
+    /// Returns the maximum number of children multiplied by number of upper
+    /// bottom trees before the leaf is promoted to the left child of its own
+    /// bottom tree.
+    fn children_inner_threshold<C: AggregationContext>(&self, aggregation_context: &C) -> usize {
+        match aggregation_context.promotion_thresholds() {
+            Some(thresholds) => self
+                .change_frequency
+                .children_inner_threshold(aggregation_context.now(), thresholds),
+            None => CHILDREN_INNER_THRESHOLD,
         }
     }
 
+    /// Returns true when the leaf should be demoted if possible.
+    fn is_cold<C: AggregationContext>(&self, aggregation_context: &C) -> bool {
+        aggregation_context
+            .promotion_thresholds()
+            .is_some_and(|thresholds| {
+                self.change_frequency
+                    .is_cold(aggregation_context.now(), thresholds)
+            })
+    }
+
     /// Captures information about the aggregation tree roots.
     pub fn get_root_info<C: AggregationContext<Info = T, ItemRef = I>>(
         &self,
@@ -142,6 +315,219 @@ impl<T, I: Clone + Eq + Hash + IsEnabled> AggregationTreeLeaf<T, I> {
     pub fn has_upper(&self) -> bool {
         !self.upper.is_unset()
     }
+
+    /// Returns the changes of this leaf that have been prepared but not
+    /// applied yet.
+    #[cfg(feature = "aggregation_barrier")]
+    pub fn pending_changes(&self) -> &PendingChanges {
+        &self.pending
+    }
+
+    /// Returns how the leaf is connected to the upper bottom trees.
+    pub fn upper_kind(&self) -> UpperKind {
+        match &self.upper {
+            BottomConnection::Left(_) => UpperKind::Left,
+            BottomConnection::Inner(_) => UpperKind::Inner,
+        }
+    }
+
+    /// Returns the number of upper bottom trees the leaf is connected to.
+    pub fn upper_count(&self) -> usize {
+        match &self.upper {
+            BottomConnection::Left(_) => 1,
+            BottomConnection::Inner(list) => list.iter().count(),
+        }
+    }
+
+    /// Returns the heights of the upper bottom trees the leaf is connected to.
+    pub fn upper_heights(&self) -> Vec<u8> {
+        match &self.upper {
+            BottomConnection::Left(upper) => vec![upper.height()],
+            BottomConnection::Inner(list) => list
+                .iter()
+                .map(|(BottomRef { upper }, _)| upper.height())
+                .collect(),
+        }
+    }
+
+    /// Returns the height of the highest bottom tree created from this leaf,
+    /// or None when no bottom tree has been created yet.
+    pub fn max_bottom_tree_height(&self) -> Option<u8> {
+        self.bottom_trees
+            .iter()
+            .rposition(|tree| tree.is_some())
+            .map(|height| height as u8)
+    }
+
+    /// Returns the depth of the deepest top tree created from this leaf, or
+    /// None when no top tree has been created yet.
+    pub fn max_top_tree_depth(&self) -> Option<u8> {
+        self.top_trees
+            .iter()
+            .rposition(|tree| tree.is_some())
+            .map(|depth| depth as u8)
+    }
+
+    /// Returns a reference to the root aggregated info of the leaf, or None
+    /// when it hasn't been requested with [super::aggregation_info] yet.
+    /// Unlike [super::aggregation_info] this never creates a top tree.
+    pub fn existing_aggregation_info(&self) -> Option<AggregationInfoReference<T>> {
+        let tree = self.top_trees.first()?.as_ref()?;
+        Some(AggregationInfoReference { tree: tree.clone() })
+    }
+
+    /// Writes a human readable description of the aggregation structure of the
+    /// leaf, i. e. the connection to the uppers and all cached trees.
+    pub fn dump(&self, out: &mut impl fmt::Write, fmt_info: &impl Fn(&T) -> String) -> fmt::Result {
+        match &self.upper {
+            BottomConnection::Left(_) => writeln!(out, "  upper: left")?,
+            BottomConnection::Inner(list) => writeln!(out, "  upper: inner ({})", list.len())?,
+        }
+        for (depth, tree) in self.top_trees.iter().enumerate() {
+            if let Some(tree) = tree {
+                let info = tree.with_info(fmt_info);
+                writeln!(out, "  top tree (depth {depth}): {info}")?;
+            }
+        }
+        for (height, tree) in self.bottom_trees.iter().enumerate() {
+            if let Some(tree) = tree {
+                let (info, following, generation) =
+                    tree.with_info(|info, following, generation| {
+                        (fmt_info(info), following, generation)
+                    });
+                writeln!(
+                    out,
+                    "  bottom tree (height {height}, {following} following, generation \
+                     {generation}): {info}"
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A change on a leaf which has been prepared under the leaf lock, but not yet
+/// applied to the aggregated nodes. It owns everything it needs, so it's [Send]
+/// when the info, item references and change are.
+///
+/// The change is always applied to the uppers at the time it was prepared.
+/// [ensure_thresholds] doesn't promote a leaf while one of its changes is
+/// pending. But creating the first bottom tree of the leaf, e. g. when the
+/// item is aggregated itself, makes it the left child of that bottom tree in
+/// the meantime. That moves the current info of the item, which already
+/// includes the change, by sending the add change to the new bottom tree and
+/// the remove change to the old uppers. So the old uppers need to receive the
+/// prepared change to balance out, and applying it to the new bottom tree
+/// instead would count it twice.
+///
+/// The new bottom tree is connected to the uppers of the old ones before the
+/// remove change is sent. So the aggregated nodes above them only miss the
+/// change until it's applied, like with any pending change. Only the old
+/// uppers themselves miss the info of the item including the change until
+/// then.
+#[must_use]
+pub struct PreparedChange<T, I: IsEnabled, Change> {
+    uppers: PreparedUppers<T, I>,
+    change: Change,
+    in_flight: SmallVec<[InFlightChange; 1]>,
+}
+
+/// Counts the changes of a leaf that have been prepared under the leaf lock,
+/// but not applied yet. The counter is allocated when the leaf prepares its
+/// first change.
+#[derive(Default)]
+struct InFlightChanges(OnceLock<Arc<AtomicU32>>);
+
+impl InFlightChanges {
+    fn is_empty(&self) -> bool {
+        self.0
+            .get()
+            .map_or(true, |count| count.load(Ordering::Acquire) == 0)
+    }
+}
+
+/// Registers a change of a leaf in [InFlightChanges] and for the change
+/// barrier until it's dropped.
+struct InFlightChange {
+    count: Arc<AtomicU32>,
+    _pending: PendingChange,
+}
+
+impl Drop for InFlightChange {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::Release);
+    }
+}
+
+enum PreparedUppers<T, I: IsEnabled> {
+    /// Most leafs have a single upper. This avoids capturing the shared upper
+    /// map, which would need to be copied when the uppers of the leaf are
+    /// modified before the change is applied.
+    Single(Arc<BottomTree<T, I>>),
+    Multiple(BottomUppers<T, I>),
+}
+
+impl<T, I: Clone + Eq + Hash + IsEnabled, Change> PreparedChange<T, I, Change> {
+    /// Applies the change to the aggregated nodes.
+    pub fn apply<C: AggregationContext<Info = T, ItemRef = I, ItemChange = Change>>(
+        self,
+        aggregation_context: &C,
+    ) {
+        match &self.uppers {
+            PreparedUppers::Single(upper) => upper.child_change(aggregation_context, &self.change),
+            PreparedUppers::Multiple(uppers) => {
+                uppers.child_change(aggregation_context, &self.change)
+            }
+        }
+    }
+
+    /// Returns a key which identifies the uppers the change has been prepared
+    /// for. Changes with equal keys are applied to the same uppers. But changes
+    /// for the same uppers might have different keys when they have been
+    /// prepared on different leafs.
+    pub fn uppers_key(&self) -> usize {
+        let ptr = match &self.uppers {
+            PreparedUppers::Single(upper) | PreparedUppers::Multiple(BottomUppers::Left(upper)) => {
+                Arc::as_ptr(upper).cast()
+            }
+            PreparedUppers::Multiple(BottomUppers::Inner(list)) => list.as_ptr(),
+        };
+        ptr as usize
+    }
+
+    /// Merges `other` into this change, so both are applied in a single
+    /// propagation. Both need to have the same [Self::uppers_key]. Returns
+    /// `other` when the changes can't be merged.
+    pub fn merge<C: AggregationContext<Info = T, ItemRef = I, ItemChange = Change>>(
+        &mut self,
+        aggregation_context: &C,
+        other: Self,
+    ) -> Option<Self> {
+        debug_assert_eq!(self.uppers_key(), other.uppers_key());
+        let PreparedChange {
+            uppers,
+            change,
+            in_flight,
+        } = other;
+        match aggregation_context.merge_change(&mut self.change, change) {
+            // The merged change might come from another leaf, so it stays in
+            // flight for that leaf too.
+            None => {
+                self.in_flight.extend(in_flight);
+                None
+            }
+            Some(change) => Some(PreparedChange {
+                uppers,
+                change,
+                in_flight,
+            }),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn has_single_upper(&self) -> bool {
+        matches!(self.uppers, PreparedUppers::Single(_))
+    }
 }
 
 fn get_or_create_in_vec<T>(
@@ -168,7 +554,7 @@ pub fn top_tree<C: AggregationContext>(
     depth: u8,
 ) -> Arc<TopTree<C::Info>> {
     let new_top_tree = {
-        let mut item = aggregation_context.item(reference);
+        let mut item = reentrancy::item(aggregation_context, reference);
         let leaf = item.leaf();
         let (tree, new) = get_or_create_in_vec(&mut leaf.top_trees, depth as usize, || {
             Arc::new(TopTree::new(depth))
@@ -192,7 +578,7 @@ pub fn bottom_tree<C: AggregationContext>(
     let new_bottom_tree;
     let mut result = None;
     {
-        let mut item = aggregation_context.item(reference);
+        let mut item = reentrancy::item(aggregation_context, reference);
         let leaf = item.leaf();
         let (tree, new) = get_or_create_in_vec(&mut leaf.bottom_trees, height as usize, || {
             Arc::new(BottomTree::new(reference.clone(), height))
@@ -228,16 +614,19 @@ pub fn add_inner_upper_to_item<C: AggregationContext>(
     nesting_level: u8,
 ) -> bool {
     let (change, children) = {
-        let mut item = aggregation_context.item(reference);
+        let mut item = reentrancy::item(aggregation_context, reference);
         let number_of_children = item.number_of_children();
         let leaf = item.leaf();
+        let threshold = leaf.children_inner_threshold(aggregation_context);
         let BottomConnection::Inner(inner) = &mut leaf.upper else {
             return false;
         };
-        if inner.len() * number_of_children > CHILDREN_INNER_THRESHOLD {
+        if inner.len() * number_of_children > threshold {
             return false;
         }
+        let entries = inner.len();
         let new = inner.add_clonable(BottomRef::ref_cast(upper), nesting_level);
+        upper.update_inner_items(entries, inner.len());
         if new {
             let change = item.get_add_change();
             (
@@ -304,6 +693,7 @@ fn add_left_upper_to_item_step_2<C: AggregationContext>(
         upper.add_children_of_child(aggregation_context, ChildLocation::Left, &children, 1)
     }
     for (BottomRef { upper: old_upper }, count) in old_inner.into_counts() {
+        old_upper.update_inner_items(1, 0);
         old_upper.migrate_old_inner(
             aggregation_context,
             reference,
@@ -319,7 +709,7 @@ pub fn remove_left_upper_from_item<C: AggregationContext>(
     reference: &C::ItemRef,
     upper: &Arc<BottomTree<C::Info, C::ItemRef>>,
 ) {
-    let mut item = aggregation_context.item(reference);
+    let mut item = reentrancy::item(aggregation_context, reference);
     let leaf = &mut item.leaf();
     leaf.upper.unset_left_upper(upper);
     let change = item.get_remove_change();
@@ -339,11 +729,14 @@ pub fn remove_inner_upper_from_item<C: AggregationContext>(
     reference: &C::ItemRef,
     upper: &Arc<BottomTree<C::Info, C::ItemRef>>,
 ) -> bool {
-    let mut item = aggregation_context.item(reference);
+    let mut item = reentrancy::item(aggregation_context, reference);
     let BottomConnection::Inner(inner) = &mut item.leaf().upper else {
         return false;
     };
-    if !inner.remove_clonable(BottomRef::ref_cast(upper)) {
+    let entries = inner.len();
+    let removed = inner.remove_clonable(BottomRef::ref_cast(upper));
+    upper.update_inner_items(entries, inner.len());
+    if !removed {
         // Nothing to do
         return true;
     }
@@ -363,9 +756,33 @@ pub fn remove_inner_upper_from_item<C: AggregationContext>(
     true
 }
 
+/// The reorganization of an item decided by [ensure_thresholds].
+enum Reorganization<C: AggregationContext> {
+    /// The item becomes the left child of its new bottom tree.
+    Promote(
+        AddLeftUpperIntermediateResult<C>,
+        Arc<BottomTree<C::Info, C::ItemRef>>,
+    ),
+    /// The item is removed from its unconnected bottom tree.
+    Demote(
+        Option<C::ItemChange>,
+        LargeStackVec<C::ItemRef>,
+        Arc<BottomTree<C::Info, C::ItemRef>>,
+    ),
+}
+
 /// Checks thresholds for an item to ensure the aggregation graph stays
 /// well-formed. Run this before adding a child to an item. Returns a closure
 /// that should be executed outside of the leaf lock.
+///
+/// With [AggregationContext::promotion_thresholds] this also demotes a cold
+/// item, when it's the left child of a bottom tree without uppers that nobody
+/// else references.
+///
+/// Nothing is reorganized while a change of the item is pending, i. e. has been
+/// prepared but not applied yet. The reorganization would move the current
+/// info of the item, which already includes the change, before the change
+/// reaches the old uppers. It's checked again on the next call.
 pub fn ensure_thresholds<'a, C: AggregationContext>(
     aggregation_context: &'a C,
     item: &mut C::ItemLock<'_>,
@@ -375,29 +792,68 @@ pub fn ensure_thresholds<'a, C: AggregationContext>(
     let number_of_total_children = item.number_of_children();
     let reference = item.reference().clone();
     let leaf = item.leaf();
-    if let BottomConnection::Inner(list) = &leaf.upper {
-        if list.len() * number_of_total_children > CHILDREN_INNER_THRESHOLD {
-            let (tree, new) = get_or_create_in_vec(&mut leaf.bottom_trees, 0, || {
-                Arc::new(BottomTree::new(reference.clone(), 0))
-            });
-            debug_assert!(new);
-            let new_bottom_tree = tree.clone();
-            result = Some((
-                add_left_upper_to_item_step_1::<C>(item, &new_bottom_tree),
-                reference,
-                new_bottom_tree,
-            ));
+    if !leaf.in_flight.is_empty() {
+        return None;
+    }
+    match &leaf.upper {
+        BottomConnection::Inner(list) => {
+            if list.len() * number_of_total_children
+                > leaf.children_inner_threshold(aggregation_context)
+            {
+                let (tree, new) = get_or_create_in_vec(&mut leaf.bottom_trees, 0, || {
+                    Arc::new(BottomTree::new(reference.clone(), 0))
+                });
+                debug_assert!(new);
+                let new_bottom_tree = tree.clone();
+                leaf.record_promotion(aggregation_context);
+                result = Some((
+                    Reorganization::<C>::Promote(
+                        add_left_upper_to_item_step_1::<C>(item, &new_bottom_tree),
+                        new_bottom_tree,
+                    ),
+                    reference,
+                ));
+            }
+        }
+        BottomConnection::Left(upper) => {
+            // The leaf references its bottom tree as upper and in the cache of
+            // bottom trees.
+            if leaf.is_cold(aggregation_context) && upper.is_released(aggregation_context, 2) {
+                let upper = upper.clone();
+                leaf.upper.unset_left_upper(&upper);
+                // Drop it from the cache, so the next bottom tree of height 0
+                // is created connected to the item again.
+                leaf.bottom_trees[0] = None;
+                let children = item.children().map(|r| r.into_owned()).collect();
+                result = Some((
+                    Reorganization::Demote(item.get_remove_change(), children, upper),
+                    reference,
+                ));
+            }
         }
     }
-    result.map(|(result, reference, new_bottom_tree)| {
-        move || {
-            let _span = tracing::trace_span!("aggregation_tree::reorganize").entered();
-            add_left_upper_to_item_step_2(
-                aggregation_context,
-                &reference,
-                &new_bottom_tree,
-                result,
-            );
+    let (result, reference) = result?;
+    let in_flight = item.leaf().start_change();
+    Some(move || {
+        let _in_flight = in_flight;
+        let _span = tracing::trace_span!("aggregation_tree::reorganize").entered();
+        match result {
+            Reorganization::Promote(result, new_bottom_tree) => {
+                add_left_upper_to_item_step_2(
+                    aggregation_context,
+                    &reference,
+                    &new_bottom_tree,
+                    result,
+                );
+            }
+            Reorganization::Demote(change, children, old_bottom_tree) => {
+                if let Some(change) = change {
+                    old_bottom_tree.child_change(aggregation_context, &change);
+                }
+                for child in children {
+                    old_bottom_tree.remove_child_of_child(aggregation_context, &child)
+                }
+            }
         }
     })
 }
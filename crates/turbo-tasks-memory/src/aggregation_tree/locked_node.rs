@@ -0,0 +1,83 @@
+use std::{
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use parking_lot::{RwLockReadGuard, RwLockWriteGuard};
+
+use super::AggregationContext;
+
+/// Identifies an aggregated node by the address of its info, which is stable as
+/// long as the aggregated node is alive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AggregatedNodeId(usize);
+
+impl AggregatedNodeId {
+    /// Returns the id of the aggregated node which owns `info`, e. g. the info
+    /// behind [super::AggregationInfoGuard].
+    pub fn of<T>(info: &T) -> Self {
+        Self(info as *const T as usize)
+    }
+}
+
+/// A lock guard of an aggregated node. Acquiring and releasing it is reported
+/// to [AggregationContext::on_lock_aggregated_node] and
+/// [AggregationContext::on_unlock_aggregated_node].
+pub struct LockedNode<'c, C: AggregationContext, G> {
+    aggregation_context: &'c C,
+    node: AggregatedNodeId,
+    guard: ManuallyDrop<G>,
+}
+
+impl<'c, C: AggregationContext, G> LockedNode<'c, C, G> {
+    /// Wraps a guard which has just been acquired. `node` identifies the
+    /// aggregated node behind the guard.
+    pub fn new(aggregation_context: &'c C, node: AggregatedNodeId, guard: G) -> Self {
+        aggregation_context.on_lock_aggregated_node(node);
+        Self {
+            aggregation_context,
+            node,
+            guard: ManuallyDrop::new(guard),
+        }
+    }
+}
+
+impl<'c, 'a, C: AggregationContext, T> LockedNode<'c, C, RwLockWriteGuard<'a, T>> {
+    /// Downgrades a write lock to a read lock. The node stays locked, so
+    /// nothing is reported.
+    pub fn downgrade(self) -> LockedNode<'c, C, RwLockReadGuard<'a, T>> {
+        let mut this = ManuallyDrop::new(self);
+        // SAFETY: `this` is never dropped, so the guard is only taken once.
+        let guard = unsafe { ManuallyDrop::take(&mut this.guard) };
+        LockedNode {
+            aggregation_context: this.aggregation_context,
+            node: this.node,
+            guard: ManuallyDrop::new(RwLockWriteGuard::downgrade(guard)),
+        }
+    }
+}
+
+impl<C: AggregationContext, G: Deref> Deref for LockedNode<'_, C, G> {
+    type Target = G::Target;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<C: AggregationContext, G: DerefMut> DerefMut for LockedNode<'_, C, G> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<C: AggregationContext, G> Drop for LockedNode<'_, C, G> {
+    fn drop(&mut self) {
+        // Reported before the lock is released, so the reported write locks of
+        // a node never overlap.
+        self.aggregation_context
+            .on_unlock_aggregated_node(self.node);
+        // SAFETY: The guard is not accessed after this.
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+    }
+}
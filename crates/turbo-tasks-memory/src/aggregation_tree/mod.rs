@@ -23,25 +23,74 @@
 //! - upper: Relationship to a aggregated node in a higher level (more
 //!   aggregated). Since all communication is strictly upwards there is no down
 //!   relationship for that.
+//!
+//! Multiple edges: The same child can be connected to a parent multiple times
+//! and the same upper can be reached via multiple paths. All upper and
+//! following relationships are reference counted, but the aggregated info has
+//! set semantics. A node is added to an upper when the first reference is
+//! created and removed when the last one is dropped, and a change is applied
+//! once per distinct upper, independent of the reference count. So
+//! [AggregationContext] doesn't need to scale changes by the number of edges.
+//!
+//! Custom statistics: There is no separate registry for statistics. An
+//! [AggregationContext] adds them to its info and change as [AggregatedData],
+//! and delegates to it in [AggregationContext::apply_change],
+//! [AggregationContext::info_to_add_change] and
+//! [AggregationContext::info_to_remove_change]. They are propagated together
+//! with the other changes, so they don't need a separate aggregation tree. A
+//! tuple of statistics is an [AggregatedData] too.
 
+#[cfg(feature = "aggregation_async")]
+mod async_apply;
+mod barrier;
 mod bottom_connection;
 mod bottom_tree;
+mod bounded;
+mod change_batch;
+#[cfg(test)]
+mod chaos_context;
+mod composed;
+mod contention;
+mod cow_info;
 mod inner_refs;
 mod leaf;
+mod locked_node;
+mod promotion;
+mod propagation_trace;
+#[cfg(test)]
+mod recording_context;
+mod reentrancy;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 #[cfg(test)]
 mod tests;
 mod top_tree;
 
-use std::{borrow::Cow, hash::Hash, ops::ControlFlow, sync::Arc};
+use std::{borrow::Cow, hash::Hash, ops::ControlFlow, sync::Arc, time::Instant};
 
 use nohash_hasher::IsEnabled;
 use smallvec::SmallVec;
 
-use self::{leaf::top_tree, top_tree::TopTree};
+#[cfg(feature = "aggregation_async")]
+pub use self::async_apply::{apply_change_async, AsyncAggregationContext};
+#[cfg(feature = "aggregation_barrier")]
+pub use self::barrier::change_barrier;
+#[cfg(feature = "aggregation_contention")]
+pub use self::contention::{
+    contention_stats, take_contention_stats, ContentionStats, HISTOGRAM_BUCKETS,
+};
 pub use self::{
-    leaf::{ensure_thresholds, AggregationTreeLeaf},
+    bounded::{BoundedCountChange, BoundedCountMap},
+    change_batch::ChangeBatch,
+    composed::AggregatedData,
+    cow_info::CowInfo,
+    leaf::{ensure_thresholds, AggregationTreeLeaf, PreparedChange, UpperKind},
+    locked_node::AggregatedNodeId,
+    promotion::PromotionThresholds,
+    reentrancy::{assert_not_applying_change, ChangeJobQueue},
     top_tree::AggregationInfoGuard,
 };
+use self::{leaf::top_tree, top_tree::TopTree};
 
 /// The maximum connectivity of one layer of bottom tree.
 const CONNECTIVITY_LIMIT: u8 = 7;
@@ -68,18 +117,35 @@ pub trait AggregationContext {
     type RootInfo;
     type RootInfoType;
 
-    /// Gets mutable access to an item.
+    /// Gets mutable access to an item. Implementations can call
+    /// [assert_not_applying_change] first, so calling it from within
+    /// [AggregationContext::apply_change] panics instead of deadlocking.
     fn item<'a>(&'a self, reference: &Self::ItemRef) -> Self::ItemLock<'a>;
 
     /// Apply a changeset to an aggregated info object. Returns a new changeset
     /// that should be applied to the next aggregation level. Might return None,
     /// if no change should be applied to the next level.
+    ///
+    /// This is called while the aggregated node is locked. It must not apply
+    /// changes to the aggregation tree itself, but can queue them in a
+    /// [ChangeJobQueue] instead.
     fn apply_change(
         &self,
         info: &mut Self::Info,
         change: &Self::ItemChange,
     ) -> Option<Self::ItemChange>;
 
+    /// Merges `other` into `change`, so both can be applied in a single
+    /// propagation. Returns `other` when the changes can't be merged.
+    fn merge_change(
+        &self,
+        change: &mut Self::ItemChange,
+        other: Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        let _ = change;
+        Some(other)
+    }
+
     /// Creates a changeset from an aggregated info object, that represents
     /// adding the aggregated node to an aggregated node of the next level.
     fn info_to_add_change(&self, info: &Self::Info) -> Option<Self::ItemChange>;
@@ -103,6 +169,42 @@ pub trait AggregationContext {
         root_info: &mut Self::RootInfo,
         other: Self::RootInfo,
     ) -> ControlFlow<()>;
+
+    /// Returns the maximum number of details, e. g. keys of a
+    /// [BoundedCountMap], an aggregated info keeps before it switches to a
+    /// summary. [Self::apply_change] passes it to
+    /// [AggregatedData::apply_change_with_limit].
+    fn detail_limit(&self) -> usize {
+        usize::MAX
+    }
+
+    /// Returns the thresholds to promote frequently changing leaves earlier.
+    /// Returns None to disable adaptive promotion, which also avoids tracking
+    /// the change frequency.
+    fn promotion_thresholds(&self) -> Option<PromotionThresholds> {
+        None
+    }
+
+    /// Returns the current time, which is used to measure the change frequency
+    /// of leaves. It's only called when [Self::promotion_thresholds] returns
+    /// thresholds.
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    /// Called after an aggregated node has been locked by the aggregation
+    /// tree, e. g. to debug the lock order. Locks taken through
+    /// [AggregationInfoReference::lock] or only to read the info for a dump
+    /// are not reported.
+    fn on_lock_aggregated_node(&self, node: AggregatedNodeId) {
+        let _ = node;
+    }
+
+    /// Called before a lock reported by [Self::on_lock_aggregated_node] is
+    /// released.
+    fn on_unlock_aggregated_node(&self, node: AggregatedNodeId) {
+        let _ = node;
+    }
 }
 
 /// A lock on a single item.
@@ -143,8 +245,15 @@ pub struct AggregationInfoReference<T> {
 }
 
 impl<T> AggregationInfoReference<T> {
-    /// Locks the info and gives mutable access to it.
+    /// Locks the info and gives mutable access to it. Cloning the locked info
+    /// is cheap when it's a [CowInfo], so this can be used to take snapshots.
     pub fn lock(&self) -> AggregationInfoGuard<T> {
         self.tree.lock_info()
     }
+
+    /// Returns the depth of the top tree behind the info. The root info of a
+    /// leaf is the top tree of depth 0.
+    pub fn depth(&self) -> u8 {
+        self.tree.depth
+    }
 }
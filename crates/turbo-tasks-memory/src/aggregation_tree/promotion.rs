@@ -0,0 +1,131 @@
+//! Adaptive promotion of leaves based on their change frequency.
+//!
+//! A leaf which is an inner child of multiple bottom trees has to propagate
+//! every change to all of them. Once the number of upper bottom trees
+//! multiplied by the number of children exceeds a threshold, the leaf is
+//! promoted, i. e. it gets its own bottom tree and becomes the left child of
+//! it. Frequently changing leaves benefit from being promoted earlier, while
+//! mostly static leaves are cheaper to keep as inner children.
+//!
+//! A promoted leaf is demoted again once it's cold and its own bottom tree
+//! isn't connected to any upper anymore, e. g. after it has been removed from
+//! all parents. Its bottom tree is released then, and new parents aggregate the
+//! leaf as inner child again. Leaves whose bottom tree is still connected are
+//! not demoted, since the uppers which aggregate the bottom tree are not known
+//! to the leaf. The bottom tree is also kept while anyone else holds a
+//! reference to it, since that one could connect an upper to it at any time.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use super::CHILDREN_INNER_THRESHOLD;
+
+/// Thresholds for adaptive promotion, provided by
+/// [super::AggregationContext::promotion_thresholds].
+#[derive(Debug, Clone, Copy)]
+pub struct PromotionThresholds {
+    /// The half life of the change frequency of a leaf.
+    pub half_life: Duration,
+    /// The change frequency (number of changes, decaying with the half life)
+    /// from which on a leaf is considered hot.
+    pub hot_change_frequency: f32,
+    /// The maximum number of children multiplied by number of upper bottom
+    /// trees for hot leaves. It's used instead of the default threshold.
+    pub hot_children_inner_threshold: usize,
+    /// The change frequency below which a leaf is considered cold and can be
+    /// demoted.
+    pub cold_change_frequency: f32,
+}
+
+/// An exponential moving average of the number of changes of a leaf. The time
+/// is provided by [super::AggregationContext::now]. Changes are recorded
+/// through a shared reference, so changing a leaf doesn't need it to be
+/// mutable.
+#[derive(Default)]
+pub struct ChangeFrequency {
+    /// The time of the first recorded change. The other times are stored as
+    /// seconds since then.
+    start: OnceLock<Instant>,
+    /// The value and the time of the last change, both as bits of a f32.
+    state: AtomicU64,
+    /// The time of the last promotion of the leaf.
+    promoted: Option<Instant>,
+}
+
+impl ChangeFrequency {
+    /// Records a change.
+    pub fn record(&self, now: Instant, half_life: Duration) {
+        let start = *self.start.get_or_init(|| now);
+        let now = now.saturating_duration_since(start).as_secs_f32();
+        // The closure always returns Some, so this can't fail.
+        let _ = self
+            .state
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |state| {
+                let (value, last_change) = unpack(state);
+                Some(pack(
+                    decay(value, now - last_change, half_life) + 1.0,
+                    now.max(last_change),
+                ))
+            });
+    }
+
+    /// Returns the change frequency at `now`.
+    pub fn get(&self, now: Instant, half_life: Duration) -> f32 {
+        let Some(start) = self.start.get() else {
+            return 0.0;
+        };
+        let now = now.saturating_duration_since(*start).as_secs_f32();
+        let (value, last_change) = unpack(self.state.load(Ordering::Relaxed));
+        decay(value, now - last_change, half_life)
+    }
+
+    /// Records that the leaf has been promoted.
+    pub fn record_promotion(&mut self, now: Instant) {
+        self.promoted = Some(now);
+    }
+
+    /// Returns true when the leaf should be demoted if possible. A leaf is not
+    /// demoted within one half life after it has been promoted, so it doesn't
+    /// flip between both states while its frequency is close to the
+    /// thresholds.
+    pub fn is_cold(&self, now: Instant, thresholds: PromotionThresholds) -> bool {
+        if self
+            .promoted
+            .is_some_and(|promoted| now.saturating_duration_since(promoted) < thresholds.half_life)
+        {
+            return false;
+        }
+        self.get(now, thresholds.half_life) < thresholds.cold_change_frequency
+    }
+
+    /// Returns the maximum number of children multiplied by number of upper
+    /// bottom trees before the leaf is promoted.
+    pub fn children_inner_threshold(&self, now: Instant, thresholds: PromotionThresholds) -> usize {
+        if self.get(now, thresholds.half_life) >= thresholds.hot_change_frequency {
+            thresholds.hot_children_inner_threshold
+        } else {
+            CHILDREN_INNER_THRESHOLD
+        }
+    }
+}
+
+fn pack(value: f32, time: f32) -> u64 {
+    ((value.to_bits() as u64) << 32) | time.to_bits() as u64
+}
+
+fn unpack(state: u64) -> (f32, f32) {
+    (
+        f32::from_bits((state >> 32) as u32),
+        f32::from_bits(state as u32),
+    )
+}
+
+/// Returns `value` after `elapsed` seconds.
+fn decay(value: f32, elapsed: f32, half_life: Duration) -> f32 {
+    value * 0.5f32.powf(elapsed.max(0.0) / half_life.as_secs_f32())
+}
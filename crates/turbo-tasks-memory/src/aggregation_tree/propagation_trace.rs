@@ -0,0 +1,71 @@
+//! Emits a tracing span for every propagation wave, i. e. the outermost
+//! propagation of a change on a thread. The spans are written by the tracing
+//! subscriber that is installed, e. g. the trace writer of turbopack, so the
+//! aggregation overhead shows up next to task execution in the trace viewer.
+//! This is only enabled with the `aggregation_tracing` feature, otherwise all
+//! functions are no-ops.
+//!
+//! The span records the id of the wave, the aggregated node where the wave
+//! started (its address, kind and height or depth) and the number of aggregated
+//! nodes the change has been applied to. The duration of the wave is the
+//! duration of the span.
+
+#[cfg(feature = "aggregation_tracing")]
+mod enabled {
+    use std::{
+        cell::{Cell, RefCell},
+        sync::atomic::{AtomicU64, Ordering},
+    };
+
+    use tracing::span::EnteredSpan;
+
+    static NEXT_WAVE: AtomicU64 = AtomicU64::new(1);
+
+    thread_local! {
+        static WAVE: RefCell<Option<EnteredSpan>> = const { RefCell::new(None) };
+        static VISITED: Cell<u64> = const { Cell::new(0) };
+    }
+
+    pub fn visit(node: usize, kind: &'static str, level: u8) {
+        WAVE.with(|wave| {
+            let mut wave = wave.borrow_mut();
+            if wave.is_none() {
+                *wave = Some(
+                    tracing::trace_span!(
+                        "aggregation_tree::propagate",
+                        wave = NEXT_WAVE.fetch_add(1, Ordering::Relaxed),
+                        origin = node,
+                        origin_kind = kind,
+                        origin_level = level,
+                        nodes = tracing::field::Empty,
+                    )
+                    .entered(),
+                );
+            }
+        });
+        VISITED.with(|visited| visited.set(visited.get() + 1));
+    }
+
+    pub fn finish_wave() {
+        let nodes = VISITED.with(|visited| visited.replace(0));
+        if let Some(span) = WAVE.with(|wave| wave.borrow_mut().take()) {
+            span.record("nodes", nodes);
+        }
+    }
+}
+
+/// Called when a change is applied to an aggregated node. The first call of a
+/// wave starts its span.
+#[inline(always)]
+pub fn visit<T>(_node: &T, _kind: &'static str, _level: u8) {
+    #[cfg(feature = "aggregation_tracing")]
+    enabled::visit(_node as *const T as usize, _kind, _level);
+}
+
+/// Called when the outermost propagation of a change on the current thread has
+/// finished.
+#[inline(always)]
+pub fn finish_wave() {
+    #[cfg(feature = "aggregation_tracing")]
+    enabled::finish_wave();
+}
@@ -0,0 +1,154 @@
+use std::{mem::take, ops::ControlFlow, time::Instant};
+
+use parking_lot::Mutex;
+
+use super::{promotion::PromotionThresholds, AggregatedNodeId, AggregationContext};
+
+/// An operation that has been performed on an [AggregationContext].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AggregationOperation<I> {
+    /// An item has been locked.
+    LockItem(I),
+    /// An aggregated node has been locked.
+    LockAggregatedNode(AggregatedNodeId),
+    /// An aggregated node is about to be unlocked.
+    UnlockAggregatedNode(AggregatedNodeId),
+    /// A change has been applied to the info of an aggregated node.
+    ApplyChange { node: AggregatedNodeId },
+    /// An aggregated node has been added to an upper node.
+    InfoToAddChange,
+    /// An aggregated node has been removed from an upper node.
+    InfoToRemoveChange,
+    /// The root info of an aggregated node has been read.
+    InfoToRootInfo,
+}
+
+/// A wrapper around an [AggregationContext] which records every operation
+/// performed on it. This allows tests to make assertions about the work done
+/// by an operation, e. g. which items have been locked and how many aggregated
+/// nodes have been updated.
+pub struct RecordingContext<C: AggregationContext> {
+    inner: C,
+    operations: Mutex<Vec<AggregationOperation<C::ItemRef>>>,
+}
+
+impl<C: AggregationContext> RecordingContext<C> {
+    pub fn new(inner: C) -> Self {
+        Self {
+            inner,
+            operations: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Returns the inner context.
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+
+    /// Returns and clears all operations recorded so far.
+    pub fn take_operations(&self) -> Vec<AggregationOperation<C::ItemRef>> {
+        take(&mut *self.operations.lock())
+    }
+
+    /// Returns and clears all operations recorded so far, only keeping the
+    /// references of all locked items.
+    pub fn take_locked_items(&self) -> Vec<C::ItemRef> {
+        self.take_operations()
+            .into_iter()
+            .filter_map(|op| match op {
+                AggregationOperation::LockItem(item) => Some(item),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn record(&self, operation: AggregationOperation<C::ItemRef>) {
+        self.operations.lock().push(operation);
+    }
+}
+
+impl<C: AggregationContext> AggregationContext for RecordingContext<C> {
+    type ItemLock<'a> = C::ItemLock<'a> where Self: 'a;
+    type Info = C::Info;
+    type ItemChange = C::ItemChange;
+    type ItemRef = C::ItemRef;
+    type RootInfo = C::RootInfo;
+    type RootInfoType = C::RootInfoType;
+
+    fn item<'a>(&'a self, reference: &Self::ItemRef) -> Self::ItemLock<'a> {
+        self.record(AggregationOperation::LockItem(reference.clone()));
+        self.inner.item(reference)
+    }
+
+    fn apply_change(
+        &self,
+        info: &mut Self::Info,
+        change: &Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        self.record(AggregationOperation::ApplyChange {
+            node: AggregatedNodeId::of(info),
+        });
+        self.inner.apply_change(info, change)
+    }
+
+    fn merge_change(
+        &self,
+        change: &mut Self::ItemChange,
+        other: Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        self.inner.merge_change(change, other)
+    }
+
+    fn info_to_add_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.record(AggregationOperation::InfoToAddChange);
+        self.inner.info_to_add_change(info)
+    }
+
+    fn info_to_remove_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.record(AggregationOperation::InfoToRemoveChange);
+        self.inner.info_to_remove_change(info)
+    }
+
+    fn new_root_info(&self, root_info_type: &Self::RootInfoType) -> Self::RootInfo {
+        self.inner.new_root_info(root_info_type)
+    }
+
+    fn info_to_root_info(
+        &self,
+        info: &Self::Info,
+        root_info_type: &Self::RootInfoType,
+    ) -> Self::RootInfo {
+        self.record(AggregationOperation::InfoToRootInfo);
+        self.inner.info_to_root_info(info, root_info_type)
+    }
+
+    fn merge_root_info(
+        &self,
+        root_info: &mut Self::RootInfo,
+        other: Self::RootInfo,
+    ) -> ControlFlow<()> {
+        self.inner.merge_root_info(root_info, other)
+    }
+
+    fn detail_limit(&self) -> usize {
+        self.inner.detail_limit()
+    }
+
+    fn promotion_thresholds(&self) -> Option<PromotionThresholds> {
+        self.inner.promotion_thresholds()
+    }
+
+    fn now(&self) -> Instant {
+        self.inner.now()
+    }
+
+    fn on_lock_aggregated_node(&self, node: AggregatedNodeId) {
+        self.record(AggregationOperation::LockAggregatedNode(node));
+        self.inner.on_lock_aggregated_node(node)
+    }
+
+    fn on_unlock_aggregated_node(&self, node: AggregatedNodeId) {
+        self.record(AggregationOperation::UnlockAggregatedNode(node));
+        self.inner.on_unlock_aggregated_node(node)
+    }
+}
@@ -0,0 +1,156 @@
+//! [AggregationContext::apply_change] is called while the aggregated node is
+//! locked and the change is still propagating to the upper nodes. So it must
+//! not reenter the aggregation tree, since that would deadlock on the locked
+//! node or apply the change twice.
+//!
+//! This is enforced with a per-thread flag, which is checked before locking an
+//! aggregated node or an item. Work that needs to modify the aggregation tree
+//! from within [AggregationContext::apply_change] can be queued in a
+//! [ChangeJobQueue] instead. The owner of the context runs the queued jobs
+//! once the change has been applied.
+
+use std::{cell::Cell, mem::take};
+
+use parking_lot::Mutex;
+
+use super::{contention, propagation_trace, AggregationContext};
+
+thread_local! {
+    static PROPAGATION_DEPTH: Cell<u32> = const { Cell::new(0) };
+    static APPLYING_CHANGE: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Panics when the current thread is within
+/// [AggregationContext::apply_change]. It's called before the aggregation tree
+/// locks an aggregated node or an item, so reentering the aggregation tree
+/// panics instead of deadlocking. Contexts can call it in
+/// [AggregationContext::item] to check their own calls too.
+pub fn assert_not_applying_change() {
+    assert!(
+        !APPLYING_CHANGE.with(|applying| applying.get()),
+        "AggregationContext::apply_change must not apply changes to the aggregation tree, use a \
+         ChangeJobQueue instead"
+    );
+}
+
+/// Marks the current thread as propagating a change through the aggregation
+/// tree. It must be created before locking the aggregated node, so it's dropped
+/// after the lock is released.
+pub struct PropagationGuard {
+    _private: (),
+}
+
+impl PropagationGuard {
+    pub fn enter() -> Self {
+        assert_not_applying_change();
+        PROPAGATION_DEPTH.with(|depth| depth.set(depth.get() + 1));
+        Self { _private: () }
+    }
+}
+
+impl Drop for PropagationGuard {
+    fn drop(&mut self) {
+        let depth = PROPAGATION_DEPTH.with(|depth| {
+            let value = depth.get() - 1;
+            depth.set(value);
+            value
+        });
+        if depth == 0 {
+            contention::finish_wave();
+            propagation_trace::finish_wave();
+        }
+    }
+}
+
+/// Returns true when the current thread propagates a change through the
+/// aggregation tree.
+#[cfg(feature = "aggregation_barrier")]
+pub fn is_propagating() -> bool {
+    PROPAGATION_DEPTH.with(|depth| depth.get()) > 0
+}
+
+/// Calls [AggregationContext::item] after checking that the current thread
+/// doesn't apply a change.
+pub fn item<'a, C: AggregationContext>(
+    aggregation_context: &'a C,
+    reference: &C::ItemRef,
+) -> C::ItemLock<'a> {
+    assert_not_applying_change();
+    aggregation_context.item(reference)
+}
+
+/// Calls [AggregationContext::apply_change] and marks the current thread as
+/// applying a change, so that [assert_not_applying_change] panics when it
+/// reenters the aggregation tree. The check needs to happen before locking,
+/// since the aggregated node would be locked before reaching this function
+/// again.
+pub fn apply_change<C: AggregationContext>(
+    aggregation_context: &C,
+    info: &mut C::Info,
+    change: &C::ItemChange,
+) -> Option<C::ItemChange> {
+    struct ApplyingChange {
+        previous: bool,
+    }
+
+    impl Drop for ApplyingChange {
+        fn drop(&mut self) {
+            APPLYING_CHANGE.with(|applying| applying.set(self.previous));
+        }
+    }
+
+    let _applying = ApplyingChange {
+        previous: APPLYING_CHANGE.with(|applying| applying.replace(true)),
+    };
+    aggregation_context.apply_change(info, change)
+}
+
+type ChangeJob<'a, C> = Box<dyn FnOnce(&C) + Send + 'a>;
+
+/// Jobs that modify the aggregation tree, queued from within
+/// [AggregationContext::apply_change]. They get the context passed, so they
+/// don't need to borrow it. The owner of the context runs them with
+/// [ChangeJobQueue::run] once the change has been applied, e. g. next to
+/// applying other queued updates.
+pub struct ChangeJobQueue<'a, C> {
+    jobs: Mutex<Vec<ChangeJob<'a, C>>>,
+}
+
+impl<'a, C> Default for ChangeJobQueue<'a, C> {
+    fn default() -> Self {
+        Self {
+            jobs: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<'a, C: AggregationContext> ChangeJobQueue<'a, C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues a job. It's not executed until [ChangeJobQueue::run] is called.
+    pub fn push(&self, job: impl FnOnce(&C) + Send + 'a) {
+        self.jobs.lock().push(Box::new(job));
+    }
+
+    /// Returns true when no job is queued.
+    pub fn is_empty(&self) -> bool {
+        self.jobs.lock().is_empty()
+    }
+
+    /// Runs all queued jobs, including the jobs they queue. Must not be called
+    /// from within [AggregationContext::apply_change].
+    pub fn run(&self, aggregation_context: &C) {
+        assert_not_applying_change();
+        loop {
+            let jobs = take(&mut *self.jobs.lock());
+            if jobs.is_empty() {
+                return;
+            }
+            for job in jobs {
+                job(aggregation_context);
+            }
+        }
+    }
+}
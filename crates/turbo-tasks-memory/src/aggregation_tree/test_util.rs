@@ -0,0 +1,621 @@
+//! A small graph of nodes with an aggregation tree, for tests of changes and
+//! edge operations. Every node has a value and the aggregated info of a node is
+//! the sum of the values of all nodes reachable from it. Other [TestData] can
+//! be aggregated instead, see [GraphBuilder::build_with_data].
+//!
+//! ```ignore
+//! let graph = GraphBuilder::new()
+//!     .node("a", 1)
+//!     .node("b", 2)
+//!     .edge("a", "b")
+//!     .build();
+//! assert_eq!(graph.aggregated_value("a"), 3);
+//! graph.change("b", 10);
+//! assert_eq!(graph.aggregated_value("a"), 13);
+//! ```
+//!
+//! Leaves and their uppers can be added in a chain instead:
+//!
+//! ```ignore
+//! let graph = GraphBuilder::new()
+//!     .leaf("a")
+//!     .upper("b")
+//!     .aggregating("b")
+//!     .build();
+//! assert_eq!(graph.aggregated_value("b"), 1);
+//! ```
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    mem::take,
+    ops::ControlFlow,
+    time::{Duration, Instant},
+};
+
+use auto_hash_map::AutoSet;
+use nohash_hasher::IsEnabled;
+use parking_lot::{Mutex, MutexGuard};
+
+pub use super::leaf::UpperKind;
+use super::{
+    aggregation_info, assert_not_applying_change, ensure_thresholds, leaf::PreparedChange,
+    AggregatedData, AggregationContext, AggregationItemLock, AggregationTreeLeaf,
+    BoundedCountChange, BoundedCountMap, ChangeBatch, PromotionThresholds,
+};
+
+/// Builds a [TestGraph] from named nodes and edges.
+#[derive(Default)]
+pub struct GraphBuilder {
+    nodes: Vec<(String, i32)>,
+    edges: Vec<(String, String)>,
+    promotion_thresholds: Option<PromotionThresholds>,
+    detail_limit: Option<usize>,
+    current: Option<String>,
+    aggregating: Vec<String>,
+}
+
+impl GraphBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a node with a value.
+    pub fn node(mut self, name: impl Into<String>, value: i32) -> Self {
+        self.nodes.push((name.into(), value));
+        self
+    }
+
+    /// Adds an edge from `parent` to `child`. Both nodes need to be added with
+    /// [GraphBuilder::node]. Edges are connected in the order they are added.
+    pub fn edge(mut self, parent: impl Into<String>, child: impl Into<String>) -> Self {
+        self.edges.push((parent.into(), child.into()));
+        self
+    }
+
+    /// Adds a node with the value 1. The following [GraphBuilder::upper] calls
+    /// add its uppers.
+    pub fn leaf(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        self.current = Some(name.clone());
+        self.node(name, 1)
+    }
+
+    /// Adds an edge from `name` to the node added last by
+    /// [GraphBuilder::leaf] or [GraphBuilder::upper]. `name` is added with the
+    /// value 0 when it doesn't exist yet. Chained calls add a chain of uppers.
+    pub fn upper(mut self, name: impl Into<String>) -> Self {
+        let name = name.into();
+        let child = self
+            .current
+            .replace(name.clone())
+            .expect("upper needs to follow leaf or upper");
+        if !self.nodes.iter().any(|(node, _)| *node == name) {
+            self.nodes.push((name.clone(), 0));
+        }
+        self.edge(name, child)
+    }
+
+    /// Creates the aggregated info of the node when the graph is built, so its
+    /// top tree exists before the first change.
+    pub fn aggregating(mut self, name: impl Into<String>) -> Self {
+        self.aggregating.push(name.into());
+        self
+    }
+
+    /// Sets the [AggregationContext::promotion_thresholds] of the context. The
+    /// change frequency is measured with the clock of the graph, which only
+    /// advances with [TestGraph::advance_time].
+    pub fn promotion_thresholds(mut self, thresholds: PromotionThresholds) -> Self {
+        self.promotion_thresholds = Some(thresholds);
+        self
+    }
+
+    /// Sets the [AggregationContext::detail_limit] of the context.
+    pub fn detail_limit(mut self, detail_limit: usize) -> Self {
+        self.detail_limit = Some(detail_limit);
+        self
+    }
+
+    /// Creates the nodes and connects the edges.
+    pub fn build(self) -> TestGraph {
+        self.build_with(|context| context)
+    }
+
+    /// Creates the nodes and connects the edges. The graph aggregates `D`
+    /// instead of the sum of the values.
+    pub fn build_with_data<D: TestData>(self) -> TestGraph<TestAggregationContext<D>> {
+        self.build_with(|context| context)
+    }
+
+    /// Creates the nodes and connects the edges. The graph uses the context
+    /// returned by `wrap`, e. g. a wrapper which records all operations.
+    pub fn build_with<D: TestData, C: TestContext>(
+        self,
+        wrap: impl FnOnce(TestAggregationContext<D>) -> C,
+    ) -> TestGraph<C> {
+        let mut names = HashMap::new();
+        let mut nodes = Vec::new();
+        for (name, value) in self.nodes {
+            let reference = TestNodeRef(nodes.len());
+            if names.insert(name.clone(), reference).is_some() {
+                panic!("node {name} is added twice");
+            }
+            nodes.push(Mutex::new(TestNodeInner {
+                children: vec![],
+                aggregation_leaf: AggregationTreeLeaf::new(),
+                value,
+            }));
+        }
+        let graph = TestGraph {
+            context: wrap(TestAggregationContext {
+                nodes,
+                promotion_thresholds: self.promotion_thresholds,
+                detail_limit: self.detail_limit.unwrap_or(usize::MAX),
+                now: Mutex::new(Instant::now()),
+                on_apply_change: None,
+            }),
+            names,
+        };
+        for (parent, child) in self.edges {
+            graph.connect(&parent, &child);
+        }
+        for name in self.aggregating {
+            aggregation_info(&graph.context, &graph.node_ref(&name));
+        }
+        graph
+    }
+}
+
+/// A graph created by [GraphBuilder].
+pub struct TestGraph<C = TestAggregationContext> {
+    context: C,
+    names: HashMap<String, TestNodeRef>,
+}
+
+impl<C: TestContext> TestGraph<C> {
+    /// Returns the aggregation context of the graph.
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    /// Returns the reference of a node, e. g. to pass it to functions of the
+    /// aggregation tree.
+    pub fn node_ref(&self, name: &str) -> TestNodeRef {
+        *self
+            .names
+            .get(name)
+            .unwrap_or_else(|| panic!("unknown node {name}"))
+    }
+
+    fn lock(&self, name: &str) -> C::ItemLock<'_> {
+        self.context.item(&self.node_ref(name))
+    }
+
+    /// Returns the value of the node itself.
+    pub fn value(&self, name: &str) -> i32 {
+        self.context.base().nodes[self.node_ref(name).0]
+            .lock()
+            .value
+    }
+
+    /// Calls `func` with the aggregated info of the node.
+    pub fn aggregated<R>(&self, name: &str, func: impl FnOnce(&C::Info) -> R) -> R {
+        func(&aggregation_info(&self.context, &self.node_ref(name)).lock())
+    }
+
+    /// Returns the sum of the values of all nodes reachable from the node.
+    pub fn aggregated_value(&self, name: &str) -> i32
+    where
+        C: AggregationContext<Info = i32>,
+    {
+        self.aggregated(name, |info| *info)
+    }
+
+    /// Returns how the node is connected to its upper bottom trees.
+    pub fn upper_kind(&self, name: &str) -> UpperKind {
+        self.lock(name).leaf().upper_kind()
+    }
+
+    /// Returns the number of upper bottom trees the node is connected to.
+    pub fn upper_count(&self, name: &str) -> usize {
+        self.lock(name).leaf().upper_count()
+    }
+
+    /// Adds `delta` to the value of the node and propagates the change after
+    /// the node lock has been released.
+    pub fn change(&self, name: &str, delta: i32) {
+        if delta != 0 {
+            self.change_job(name, delta)();
+        }
+    }
+
+    /// Advances the clock of the graph, which is used to measure the change
+    /// frequency of nodes.
+    pub fn advance_time(&self, duration: Duration) {
+        *self.context.base().now.lock() += duration;
+    }
+
+    /// Returns the height of the highest bottom tree created from the node, or
+    /// None when no bottom tree has been created yet.
+    pub fn max_bottom_tree_height(&self, name: &str) -> Option<u8> {
+        self.lock(name).leaf().max_bottom_tree_height()
+    }
+
+    /// Returns the heights of the upper bottom trees the node is connected to.
+    pub fn upper_heights(&self, name: &str) -> Vec<u8> {
+        self.lock(name).leaf().upper_heights()
+    }
+
+    /// Returns the depth of the deepest top tree created from the node, or None
+    /// when no top tree has been created yet.
+    pub fn max_top_tree_depth(&self, name: &str) -> Option<u8> {
+        self.lock(name).leaf().max_top_tree_depth()
+    }
+
+    /// Adds `delta` to the value of the node and returns the job which
+    /// propagates the change, like [AggregationTreeLeaf::change_job].
+    pub fn change_job(&self, name: &str, delta: i32) -> impl FnOnce() + '_ {
+        let mut lock = self.lock(name);
+        C::node_guard(&mut lock).add_value(delta);
+        lock.leaf()
+            .change_job(&self.context, C::value_change(self.node_ref(name), delta))
+    }
+
+    /// Like [Self::change_job], but without the single upper fast path, see
+    /// [AggregationTreeLeaf::change_job_without_fast_path].
+    pub fn change_job_without_fast_path(&self, name: &str, delta: i32) -> impl FnOnce() + '_ {
+        let mut lock = self.lock(name);
+        C::node_guard(&mut lock).add_value(delta);
+        lock.leaf().change_job_without_fast_path(
+            &self.context,
+            C::value_change(self.node_ref(name), delta),
+        )
+    }
+
+    /// Adds `delta` to the value of the node and prepares the change without
+    /// applying it.
+    pub fn prepare_change(
+        &self,
+        name: &str,
+        delta: i32,
+    ) -> PreparedChange<C::Info, TestNodeRef, C::ItemChange> {
+        let mut lock = self.lock(name);
+        C::node_guard(&mut lock).add_value(delta);
+        lock.leaf()
+            .prepare_change(&self.context, C::value_change(self.node_ref(name), delta))
+    }
+
+    /// Adds `delta` to the value of the node and adds the change to `batch`.
+    pub fn add_to_batch(&self, batch: &mut ChangeBatch<C>, name: &str, delta: i32) {
+        let mut lock = self.lock(name);
+        C::node_guard(&mut lock).add_value(delta);
+        batch.add(
+            &self.context,
+            lock.leaf(),
+            C::value_change(self.node_ref(name), delta),
+        );
+    }
+
+    /// Adds an edge from `parent` to `child`.
+    pub fn connect(&self, parent: &str, child: &str) {
+        let child = self.node_ref(child);
+        let mut lock = self.lock(parent);
+        while let Some(job) = ensure_thresholds(&self.context, &mut lock) {
+            drop(lock);
+            job();
+            lock = self.lock(parent);
+        }
+        C::node_guard(&mut lock).guard.children.push(child);
+        let job = lock.leaf().add_child_job(&self.context, &child);
+        drop(lock);
+        job();
+    }
+
+    /// Removes the value and all edges of the node from the aggregated nodes
+    /// in a single traversal, like [AggregationTreeLeaf::remove_all_job].
+    pub fn remove_all(&self, name: &str) {
+        let mut lock = self.lock(name);
+        let remove_change = lock.get_remove_change();
+        let guard = &mut C::node_guard(&mut lock).guard;
+        guard.value = 0;
+        let children = take(&mut guard.children)
+            .into_iter()
+            .collect::<AutoSet<_>>();
+        let job = lock
+            .leaf()
+            .remove_all_job(&self.context, remove_change, children);
+        drop(lock);
+        job();
+    }
+
+    /// Removes one edge from `parent` to `child`.
+    pub fn disconnect(&self, parent: &str, child: &str) {
+        let child_ref = self.node_ref(child);
+        let mut lock = self.lock(parent);
+        let children = &mut C::node_guard(&mut lock).guard.children;
+        let index = children
+            .iter()
+            .position(|c| *c == child_ref)
+            .unwrap_or_else(|| panic!("there is no edge from {parent} to {child}"));
+        children.remove(index);
+        lock.leaf().remove_child(&self.context, &child_ref);
+    }
+}
+
+impl<D: TestData> TestGraph<TestAggregationContext<D>> {
+    /// Calls `hook` with the context every time a change is applied to an
+    /// aggregated node, i. e. from within [AggregationContext::apply_change].
+    /// That's e. g. useful to test changes made or queued from there.
+    pub fn on_apply_change(
+        &mut self,
+        hook: impl Fn(&TestAggregationContext<D>) + Send + Sync + 'static,
+    ) {
+        self.context.on_apply_change = Some(Box::new(hook));
+    }
+}
+
+/// The aggregated info of a [TestGraph].
+pub trait TestData: AggregatedData {
+    /// Returns the change of adding `delta` to the value of `node`.
+    fn value_change(node: TestNodeRef, delta: i32) -> Self::Change;
+}
+
+/// Sums up the values.
+impl AggregatedData for i32 {
+    type Change = i32;
+
+    fn merge_change(change: &mut i32, other: i32) {
+        *change += other;
+    }
+
+    fn apply_change(&mut self, change: &i32) -> Option<i32> {
+        *self += change;
+        Some(*change)
+    }
+
+    fn to_add_change(&self) -> Option<i32> {
+        (*self != 0).then_some(*self)
+    }
+
+    fn to_remove_change(&self) -> Option<i32> {
+        (*self != 0).then_some(-*self)
+    }
+}
+
+impl TestData for i32 {
+    fn value_change(_node: TestNodeRef, delta: i32) -> i32 {
+        delta
+    }
+}
+
+/// Counts the values per node.
+impl TestData for BoundedCountMap<TestNodeRef> {
+    fn value_change(node: TestNodeRef, delta: i32) -> BoundedCountChange<TestNodeRef> {
+        BoundedCountChange::Entries([(node, delta)].into_iter().collect())
+    }
+}
+
+impl<A: TestData, B: TestData> TestData for (A, B) {
+    fn value_change(node: TestNodeRef, delta: i32) -> Self::Change {
+        (
+            Some(A::value_change(node, delta)),
+            Some(B::value_change(node, delta)),
+        )
+    }
+}
+
+/// An [AggregationContext] over the nodes of a [TestGraph]. That's either a
+/// [TestAggregationContext] or a wrapper around it.
+pub trait TestContext: AggregationContext<ItemRef = TestNodeRef> + Sized {
+    /// The aggregated info of the nodes.
+    type Data: TestData;
+
+    /// Returns the context which owns the nodes.
+    fn base(&self) -> &TestAggregationContext<Self::Data>;
+
+    /// Returns the change of adding `delta` to the value of `node`.
+    fn value_change(node: TestNodeRef, delta: i32) -> Self::ItemChange;
+
+    /// Returns the guard of the node behind an item lock.
+    fn node_guard<'l, 'a>(
+        lock: &'l mut Self::ItemLock<'a>,
+    ) -> &'l mut TestNodeGuard<'a, Self::Data>
+    where
+        Self: 'a;
+}
+
+struct TestNodeInner<D> {
+    children: Vec<TestNodeRef>,
+    aggregation_leaf: AggregationTreeLeaf<D, TestNodeRef>,
+    value: i32,
+}
+
+/// A reference to a node of a [TestGraph].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TestNodeRef(usize);
+
+impl IsEnabled for TestNodeRef {}
+
+/// A locked node of a [TestGraph].
+pub struct TestNodeGuard<'a, D = i32> {
+    guard: MutexGuard<'a, TestNodeInner<D>>,
+    node: TestNodeRef,
+}
+
+impl<'a, D> TestNodeGuard<'a, D> {
+    /// Adds `delta` to the value of the node. The change still needs to be
+    /// propagated to the aggregated nodes.
+    pub fn add_value(&mut self, delta: i32) {
+        self.guard.value += delta;
+    }
+}
+
+impl<'a, D: TestData> AggregationItemLock for TestNodeGuard<'a, D> {
+    type Info = D;
+    type ItemRef = TestNodeRef;
+    type ItemChange = D::Change;
+    type ChildrenIter<'c> = impl Iterator<Item = Cow<'c, TestNodeRef>> + 'c where Self: 'c;
+
+    fn reference(&self) -> &Self::ItemRef {
+        &self.node
+    }
+
+    fn leaf(&mut self) -> &mut AggregationTreeLeaf<D, TestNodeRef> {
+        &mut self.guard.aggregation_leaf
+    }
+
+    fn number_of_children(&self) -> usize {
+        self.guard.children.len()
+    }
+
+    fn children(&self) -> Self::ChildrenIter<'_> {
+        self.guard.children.iter().map(Cow::Borrowed)
+    }
+
+    fn get_remove_change(&self) -> Option<D::Change> {
+        (self.guard.value != 0).then(|| D::value_change(self.node, -self.guard.value))
+    }
+
+    fn get_add_change(&self) -> Option<D::Change> {
+        (self.guard.value != 0).then(|| D::value_change(self.node, self.guard.value))
+    }
+}
+
+/// The [AggregationContext] of a [TestGraph]. It owns the nodes of the graph
+/// and aggregates them as `D`.
+pub struct TestAggregationContext<D = i32> {
+    nodes: Vec<Mutex<TestNodeInner<D>>>,
+    promotion_thresholds: Option<PromotionThresholds>,
+    detail_limit: usize,
+    now: Mutex<Instant>,
+    on_apply_change: Option<ApplyChangeHook<D>>,
+}
+
+type ApplyChangeHook<D> = Box<dyn Fn(&TestAggregationContext<D>) + Send + Sync>;
+
+impl<D: TestData> AggregationContext for TestAggregationContext<D> {
+    type ItemLock<'a> = TestNodeGuard<'a, D> where Self: 'a;
+    type Info = D;
+    type ItemChange = D::Change;
+    type ItemRef = TestNodeRef;
+    type RootInfo = ();
+    type RootInfoType = ();
+
+    fn item<'a>(&'a self, reference: &Self::ItemRef) -> Self::ItemLock<'a> {
+        assert_not_applying_change();
+        TestNodeGuard {
+            guard: self.nodes[reference.0].lock(),
+            node: *reference,
+        }
+    }
+
+    fn apply_change(&self, info: &mut D, change: &D::Change) -> Option<D::Change> {
+        if let Some(hook) = &self.on_apply_change {
+            hook(self);
+        }
+        info.apply_change_with_limit(self.detail_limit, change)
+    }
+
+    fn merge_change(&self, change: &mut D::Change, other: D::Change) -> Option<D::Change> {
+        D::merge_change(change, other);
+        None
+    }
+
+    fn info_to_add_change(&self, info: &D) -> Option<D::Change> {
+        info.to_add_change()
+    }
+
+    fn info_to_remove_change(&self, info: &D) -> Option<D::Change> {
+        info.to_remove_change()
+    }
+
+    fn new_root_info(&self, _root_info_type: &()) -> Self::RootInfo {}
+
+    fn info_to_root_info(&self, _info: &D, _root_info_type: &()) -> Self::RootInfo {}
+
+    fn merge_root_info(&self, _root_info: &mut (), _other: ()) -> ControlFlow<()> {
+        ControlFlow::Break(())
+    }
+
+    fn promotion_thresholds(&self) -> Option<PromotionThresholds> {
+        self.promotion_thresholds
+    }
+
+    fn detail_limit(&self) -> usize {
+        self.detail_limit
+    }
+
+    fn now(&self) -> Instant {
+        *self.now.lock()
+    }
+}
+
+#[cfg(feature = "aggregation_async")]
+impl<D: TestData> super::AsyncAggregationContext for TestAggregationContext<D> {
+    fn item_async<'a>(
+        &'a self,
+        reference: &TestNodeRef,
+    ) -> impl std::future::Future<Output = TestNodeGuard<'a, D>> + 'a {
+        std::future::ready(self.item(reference))
+    }
+}
+
+impl<D: TestData> TestContext for TestAggregationContext<D> {
+    type Data = D;
+
+    fn base(&self) -> &TestAggregationContext<D> {
+        self
+    }
+
+    fn value_change(node: TestNodeRef, delta: i32) -> D::Change {
+        D::value_change(node, delta)
+    }
+
+    fn node_guard<'l, 'a>(lock: &'l mut TestNodeGuard<'a, D>) -> &'l mut TestNodeGuard<'a, D>
+    where
+        Self: 'a,
+    {
+        lock
+    }
+}
+
+#[cfg(test)]
+impl<C: TestContext> TestContext for super::recording_context::RecordingContext<C> {
+    type Data = C::Data;
+
+    fn base(&self) -> &TestAggregationContext<C::Data> {
+        self.inner().base()
+    }
+
+    fn value_change(node: TestNodeRef, delta: i32) -> C::ItemChange {
+        C::value_change(node, delta)
+    }
+
+    fn node_guard<'l, 'a>(lock: &'l mut Self::ItemLock<'a>) -> &'l mut TestNodeGuard<'a, C::Data>
+    where
+        Self: 'a,
+    {
+        C::node_guard(lock)
+    }
+}
+
+#[cfg(test)]
+impl<C: TestContext> TestContext for super::chaos_context::ChaosContext<C> {
+    type Data = C::Data;
+
+    fn base(&self) -> &TestAggregationContext<C::Data> {
+        self.inner().base()
+    }
+
+    fn value_change(node: TestNodeRef, delta: i32) -> C::ItemChange {
+        C::value_change(node, delta)
+    }
+
+    fn node_guard<'l, 'a>(lock: &'l mut Self::ItemLock<'a>) -> &'l mut TestNodeGuard<'a, C::Data>
+    where
+        Self: 'a,
+    {
+        C::node_guard(lock)
+    }
+}
@@ -1,22 +1,35 @@
 use std::{
     borrow::Cow,
+    collections::HashSet,
     hash::Hash,
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicBool, AtomicU32, Ordering},
         Arc,
     },
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use nohash_hasher::IsEnabled;
 use parking_lot::{Mutex, MutexGuard};
 use ref_cast::RefCast;
 
-use super::{aggregation_info, AggregationContext, AggregationItemLock, AggregationTreeLeaf};
+use super::{
+    aggregation_info,
+    bounded::{BoundedCountChange, BoundedCountMap},
+    change_batch::ChangeBatch,
+    chaos_context::ChaosContext,
+    composed::AggregatedData,
+    cow_info::CowInfo,
+    leaf::UpperKind,
+    promotion::{ChangeFrequency, PromotionThresholds},
+    recording_context::{AggregationOperation, RecordingContext},
+    test_util::{GraphBuilder, TestAggregationContext, TestData, TestGraph, TestNodeRef},
+    AggregatedNodeId, AggregationContext, AggregationItemLock, AggregationTreeLeaf, ChangeJobQueue,
+};
 use crate::aggregation_tree::{bottom_tree::print_graph, leaf::ensure_thresholds};
 
-struct Node {
-    inner: Mutex<NodeInner>,
+struct Node<I = Aggregated> {
+    inner: Mutex<NodeInner<I>>,
 }
 
 impl Node {
@@ -29,6 +42,16 @@ impl Node {
     }
 }
 
+impl Node<CowInfo<Aggregated>> {
+    fn incr(&self, aggregation_context: &CowNodeAggregationContext) {
+        let mut guard = self.inner.lock();
+        guard.value += 10000;
+        guard
+            .aggregation_leaf
+            .change(aggregation_context, &Change { value: 10000 });
+    }
+}
+
 #[derive(Copy, Clone)]
 struct Change {
     value: i32,
@@ -40,9 +63,9 @@ impl Change {
     }
 }
 
-struct NodeInner {
-    children: Vec<Arc<Node>>,
-    aggregation_leaf: AggregationTreeLeaf<Aggregated, NodeRef>,
+struct NodeInner<I = Aggregated> {
+    children: Vec<Arc<Node<I>>>,
+    aggregation_leaf: AggregationTreeLeaf<I, NodeRef<I>>,
     value: u32,
 }
 
@@ -55,31 +78,31 @@ struct NodeAggregationContext<'a> {
 
 #[derive(Clone, RefCast)]
 #[repr(transparent)]
-struct NodeRef(Arc<Node>);
+struct NodeRef<I = Aggregated>(Arc<Node<I>>);
 
-impl Hash for NodeRef {
+impl<I> Hash for NodeRef<I> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         Arc::as_ptr(&self.0).hash(state);
     }
 }
 
-impl IsEnabled for NodeRef {}
+impl<I> IsEnabled for NodeRef<I> {}
 
-impl PartialEq for NodeRef {
+impl<I> PartialEq for NodeRef<I> {
     fn eq(&self, other: &Self) -> bool {
         Arc::ptr_eq(&self.0, &other.0)
     }
 }
 
-impl Eq for NodeRef {}
+impl<I> Eq for NodeRef<I> {}
 
-struct NodeGuard {
-    guard: MutexGuard<'static, NodeInner>,
-    node: Arc<Node>,
+struct NodeGuard<I: 'static = Aggregated> {
+    guard: MutexGuard<'static, NodeInner<I>>,
+    node: Arc<Node<I>>,
 }
 
-impl NodeGuard {
-    unsafe fn new(guard: MutexGuard<'_, NodeInner>, node: Arc<Node>) -> Self {
+impl<I: 'static> NodeGuard<I> {
+    unsafe fn new(guard: MutexGuard<'_, NodeInner<I>>, node: Arc<Node<I>>) -> Self {
         NodeGuard {
             guard: unsafe { std::mem::transmute(guard) },
             node,
@@ -87,17 +110,17 @@ impl NodeGuard {
     }
 }
 
-impl AggregationItemLock for NodeGuard {
-    type Info = Aggregated;
-    type ItemRef = NodeRef;
+impl<I: 'static> AggregationItemLock for NodeGuard<I> {
+    type Info = I;
+    type ItemRef = NodeRef<I>;
     type ItemChange = Change;
-    type ChildrenIter<'a> = impl Iterator<Item = Cow<'a, NodeRef>> + 'a;
+    type ChildrenIter<'a> = impl Iterator<Item = Cow<'a, NodeRef<I>>> + 'a;
 
     fn reference(&self) -> &Self::ItemRef {
         NodeRef::ref_cast(&self.node)
     }
 
-    fn leaf(&mut self) -> &mut AggregationTreeLeaf<Aggregated, NodeRef> {
+    fn leaf(&mut self) -> &mut AggregationTreeLeaf<I, NodeRef<I>> {
         &mut self.guard.aggregation_leaf
     }
 
@@ -157,6 +180,11 @@ impl<'a> AggregationContext for NodeAggregationContext<'a> {
         Some(*change)
     }
 
+    fn merge_change(&self, change: &mut Change, other: Change) -> Option<Change> {
+        change.value += other.value;
+        None
+    }
+
     fn info_to_add_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
         let change = Change { value: info.value };
         if change.is_empty() {
@@ -211,12 +239,70 @@ impl<'a> AggregationContext for NodeAggregationContext<'a> {
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 struct Aggregated {
     value: i32,
     active: bool,
 }
 
+/// Like [NodeAggregationContext], but stores the info as [CowInfo].
+struct CowNodeAggregationContext<'a> {
+    inner: NodeAggregationContext<'a>,
+}
+
+impl<'a> AggregationContext for CowNodeAggregationContext<'a> {
+    type ItemLock<'l> = NodeGuard<CowInfo<Aggregated>> where Self: 'l;
+    type Info = CowInfo<Aggregated>;
+    type ItemRef = NodeRef<CowInfo<Aggregated>>;
+    type ItemChange = Change;
+
+    fn item<'b>(&'b self, reference: &Self::ItemRef) -> Self::ItemLock<'b> {
+        let r = reference.0.clone();
+        let guard = reference.0.inner.lock();
+        unsafe { NodeGuard::new(guard, r) }
+    }
+
+    fn apply_change(&self, info: &mut CowInfo<Aggregated>, change: &Change) -> Option<Change> {
+        self.inner.apply_change(info.make_mut(), change)
+    }
+
+    fn merge_change(&self, change: &mut Change, other: Change) -> Option<Change> {
+        self.inner.merge_change(change, other)
+    }
+
+    fn info_to_add_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.inner.info_to_add_change(info)
+    }
+
+    fn info_to_remove_change(&self, info: &Self::Info) -> Option<Self::ItemChange> {
+        self.inner.info_to_remove_change(info)
+    }
+
+    type RootInfo = bool;
+
+    type RootInfoType = ();
+
+    fn new_root_info(&self, root_info_type: &Self::RootInfoType) -> Self::RootInfo {
+        self.inner.new_root_info(root_info_type)
+    }
+
+    fn info_to_root_info(
+        &self,
+        info: &Self::Info,
+        root_info_type: &Self::RootInfoType,
+    ) -> Self::RootInfo {
+        self.inner.info_to_root_info(info, root_info_type)
+    }
+
+    fn merge_root_info(
+        &self,
+        root_info: &mut Self::RootInfo,
+        other: Self::RootInfo,
+    ) -> std::ops::ControlFlow<()> {
+        self.inner.merge_root_info(root_info, other)
+    }
+}
+
 #[test]
 fn chain() {
     let something_with_lifetime = 0;
@@ -358,6 +444,1227 @@ fn chain_double_connected() {
     ctx.additions.store(0, Ordering::SeqCst);
 }
 
+#[test]
+fn snapshot() {
+    let something_with_lifetime = 0;
+    let ctx = CowNodeAggregationContext {
+        inner: NodeAggregationContext {
+            additions: AtomicU32::new(0),
+            something_with_lifetime: &something_with_lifetime,
+            add_value: true,
+        },
+    };
+    let leaf = Arc::new(Node {
+        inner: Mutex::new(NodeInner {
+            children: vec![],
+            aggregation_leaf: AggregationTreeLeaf::new(),
+            value: 1,
+        }),
+    });
+    let root = NodeRef(Arc::new(Node {
+        inner: Mutex::new(NodeInner {
+            children: vec![leaf.clone()],
+            aggregation_leaf: AggregationTreeLeaf::new(),
+            value: 2,
+        }),
+    }));
+
+    let aggregated = aggregation_info(&ctx, &root);
+    let before = aggregated.lock().clone();
+    assert_eq!(before.value, 3);
+    // Taking a snapshot only copies a pointer
+    assert!(std::ptr::eq(&*before, &*aggregated.lock().clone()));
+
+    // The snapshot is alive, so the change clones the info
+    leaf.incr(&ctx);
+    assert_eq!(before.value, 3);
+    let after = aggregated.lock().clone();
+    assert_eq!(after.value, 10003);
+    assert!(!std::ptr::eq(&*before, &*after));
+
+    // No snapshot is alive, so the change is applied in place
+    let ptr = &*after as *const Aggregated;
+    drop(before);
+    drop(after);
+    leaf.incr(&ctx);
+    let current = aggregated.lock().clone();
+    assert_eq!(current.value, 20003);
+    assert_eq!(&*current as *const Aggregated, ptr);
+}
+
+#[test]
+fn recorded_operations() {
+    let mut builder = GraphBuilder::new().node("1", 1);
+    for i in 2..=10 {
+        builder = builder
+            .node(i.to_string(), i)
+            .edge(i.to_string(), (i - 1).to_string());
+    }
+    let graph = builder.build_with(RecordingContext::new);
+    let ctx = graph.context();
+
+    let root = {
+        let aggregated = aggregation_info(ctx, &graph.node_ref("10"));
+        let aggregated = aggregated.lock();
+        assert_eq!(*aggregated, 55);
+        AggregatedNodeId::of(&*aggregated)
+    };
+    let locked = ctx.take_locked_items();
+    assert!(locked.contains(&graph.node_ref("10")));
+    assert!(locked.contains(&graph.node_ref("1")));
+
+    // A change on a leaf doesn't need to lock any other item and only updates
+    // aggregated nodes
+    graph.change("1", 10000);
+    let mut operations = ctx.take_operations();
+    assert_eq!(
+        operations.remove(0),
+        AggregationOperation::LockItem(graph.node_ref("1"))
+    );
+    let mut held = Vec::new();
+    let mut nodes = Vec::new();
+    for op in operations {
+        match op {
+            AggregationOperation::LockAggregatedNode(node) => held.push(node),
+            // The change is propagated while the lower node is still locked, so
+            // locks are released in reverse order
+            AggregationOperation::UnlockAggregatedNode(node) => {
+                assert_eq!(held.pop(), Some(node))
+            }
+            AggregationOperation::ApplyChange { node } => {
+                assert_eq!(held.last(), Some(&node));
+                nodes.push(node);
+            }
+            op => panic!("unexpected operation {op:?}"),
+        }
+    }
+    assert!(held.is_empty());
+    // Every aggregated node receives the change once, including the root
+    assert!(nodes.contains(&root));
+    assert_eq!(nodes.iter().collect::<HashSet<_>>().len(), nodes.len());
+
+    // Reading the aggregated info again doesn't do any work
+    assert_eq!(graph.aggregated_value("10"), 10055);
+    assert_eq!(ctx.take_locked_items(), vec![graph.node_ref("10")]);
+}
+
+#[test]
+fn duplicate_edges() {
+    let graph = GraphBuilder::new()
+        .node("root", 100)
+        .node("middle", 10)
+        .node("leaf", 1)
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 100);
+
+    // Every node is only aggregated once, no matter how many edges lead to it
+    graph.connect("root", "middle");
+    graph.connect("root", "middle");
+    graph.connect("middle", "leaf");
+    graph.connect("root", "leaf");
+    assert_eq!(graph.aggregated_value("root"), 111);
+
+    // Changes are applied once per upper, not once per edge
+    graph.change("leaf", 10000);
+    assert_eq!(graph.aggregated_value("root"), 10111);
+
+    // The node stays aggregated until the last edge is removed
+    graph.disconnect("root", "middle");
+    assert_eq!(graph.aggregated_value("root"), 10111);
+    graph.disconnect("root", "middle");
+    assert_eq!(graph.aggregated_value("root"), 10101);
+    graph.disconnect("root", "leaf");
+    assert_eq!(graph.aggregated_value("root"), 100);
+}
+
+#[test]
+fn remove_all() {
+    let mut builder = GraphBuilder::new()
+        .node("root", 1000)
+        .node("node", 100)
+        .edge("root", "node");
+    for i in 1..=5 {
+        builder = builder.node(i.to_string(), i).edge("node", i.to_string());
+    }
+    let graph = builder.build_with(RecordingContext::new);
+    let ctx = graph.context();
+    assert_eq!(graph.aggregated_value("root"), 1115);
+    ctx.take_operations();
+
+    graph.remove_all("node");
+
+    assert_eq!(graph.aggregated_value("root"), 1000);
+    // The node itself is only locked to take its children, not again while
+    // they are removed
+    let node = graph.node_ref("node");
+    let locked = ctx.take_locked_items();
+    assert_eq!(locked.iter().filter(|item| **item == node).count(), 1);
+}
+
+#[test]
+fn change_batch() {
+    let mut builder = GraphBuilder::new().node("1", 1);
+    for i in 2..=10 {
+        builder = builder
+            .node(i.to_string(), i)
+            .edge(i.to_string(), (i - 1).to_string());
+    }
+    let graph = builder.build_with(RecordingContext::new);
+    let ctx = graph.context();
+    assert_eq!(graph.aggregated_value("10"), 55);
+    ctx.take_operations();
+
+    graph.change("1", 1);
+    let mut single_change = ctx.take_operations();
+    assert_eq!(
+        single_change.remove(0),
+        AggregationOperation::LockItem(graph.node_ref("1"))
+    );
+
+    let mut batch = ChangeBatch::new();
+    for _ in 0..10 {
+        graph.add_to_batch(&mut batch, "1", 1);
+    }
+    assert_eq!(ctx.take_locked_items(), vec![graph.node_ref("1"); 10]);
+    batch.flush(ctx);
+
+    // All changes are propagated at once
+    assert_eq!(ctx.take_operations(), single_change);
+    assert_eq!(graph.aggregated_value("10"), 66);
+}
+
+#[test]
+fn change_batch_with_modified_uppers() {
+    let graph = GraphBuilder::new()
+        .node("root1", 100)
+        .node("root2", 100)
+        .node("leaf", 1)
+        .edge("root1", "leaf")
+        .build();
+    assert_eq!(graph.aggregated_value("root1"), 101);
+    assert_eq!(graph.aggregated_value("root2"), 100);
+
+    let mut batch = ChangeBatch::new();
+    graph.add_to_batch(&mut batch, "leaf", 10);
+    // The new upper receives the current value of the leaf, which already
+    // includes the change. So the batch must not apply it there again.
+    graph.connect("root2", "leaf");
+    batch.flush(graph.context());
+
+    assert_eq!(graph.aggregated_value("root1"), 111);
+    assert_eq!(graph.aggregated_value("root2"), 111);
+}
+
+/// Changes another node once from within [AggregationContext::apply_change],
+/// either directly or with `jobs`.
+fn reentrant_graph(
+    jobs: Option<Arc<ChangeJobQueue<'static, TestAggregationContext>>>,
+) -> TestGraph {
+    let mut graph = GraphBuilder::new()
+        .node("root", 3)
+        .node("leaf", 1)
+        .node("other", 2)
+        .edge("root", "leaf")
+        .edge("root", "other")
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 6);
+    let other = graph.node_ref("other");
+    let changed = AtomicBool::new(false);
+    graph.on_apply_change(move |context| {
+        if changed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        match &jobs {
+            Some(jobs) => jobs.push(move |context: &TestAggregationContext| {
+                context.item(&other).leaf().change(context, &1);
+            }),
+            None => context.item(&other).leaf().change(context, &1),
+        }
+    });
+    graph
+}
+
+#[test]
+fn deferred_change_jobs() {
+    let jobs = Arc::new(ChangeJobQueue::new());
+    let graph = reentrant_graph(Some(jobs.clone()));
+    graph.change("leaf", 1);
+    assert_eq!(graph.aggregated_value("root"), 7);
+    assert!(!jobs.is_empty());
+
+    jobs.run(graph.context());
+    assert!(jobs.is_empty());
+    assert_eq!(graph.aggregated_value("root"), 8);
+}
+
+#[test]
+#[should_panic(expected = "must not apply changes to the aggregation tree")]
+fn reentrant_apply_change() {
+    // Both leaves share the same upper. Without the check this would deadlock
+    // on it.
+    let graph = reentrant_graph(None);
+    graph.change("leaf", 1);
+}
+
+#[test]
+fn generation() {
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build();
+    let aggregated = aggregation_info(graph.context(), &graph.node_ref("root"));
+    let generation = aggregated.lock().generation();
+    {
+        let guard = aggregated.lock();
+        assert_eq!(*guard, 3);
+        assert_eq!(guard.generation(), generation);
+    }
+
+    graph.change("leaf", 10000);
+    let generation_after_change = aggregated.lock().generation();
+    assert!(generation_after_change > generation);
+
+    // Mutable access through the guard counts as a change too
+    {
+        let mut guard = aggregated.lock();
+        assert_eq!(guard.generation(), generation_after_change);
+        *guard = 10003;
+        assert!(guard.generation() > generation_after_change);
+    }
+    assert!(aggregated.lock().generation() > generation_after_change);
+}
+
+#[test]
+fn composed_data() {
+    /// Sum of all values.
+    #[derive(Default)]
+    struct Sum(i32);
+
+    impl AggregatedData for Sum {
+        type Change = i32;
+
+        fn merge_change(change: &mut i32, other: i32) {
+            *change += other;
+        }
+
+        fn apply_change(&mut self, change: &i32) -> Option<i32> {
+            self.0 += change;
+            Some(*change)
+        }
+
+        fn to_add_change(&self) -> Option<i32> {
+            (self.0 != 0).then_some(self.0)
+        }
+
+        fn to_remove_change(&self) -> Option<i32> {
+            (self.0 != 0).then_some(-self.0)
+        }
+    }
+
+    /// Number of lower nodes that are non-empty. Only transitions are
+    /// propagated.
+    #[derive(Default)]
+    struct NonEmpty(i32);
+
+    impl AggregatedData for NonEmpty {
+        type Change = i32;
+
+        fn merge_change(change: &mut i32, other: i32) {
+            *change += other;
+        }
+
+        fn apply_change(&mut self, change: &i32) -> Option<i32> {
+            let was_empty = self.0 == 0;
+            self.0 += change;
+            match (was_empty, self.0 == 0) {
+                (true, false) => Some(1),
+                (false, true) => Some(-1),
+                _ => None,
+            }
+        }
+
+        fn to_add_change(&self) -> Option<i32> {
+            (self.0 != 0).then_some(1)
+        }
+
+        fn to_remove_change(&self) -> Option<i32> {
+            (self.0 != 0).then_some(-1)
+        }
+    }
+
+    let mut data = <(Sum, NonEmpty)>::default();
+    assert!(data.to_add_change().is_none());
+
+    assert_eq!(
+        data.apply_change(&(Some(5), Some(1))),
+        Some((Some(5), Some(1)))
+    );
+    assert_eq!(
+        data.apply_change(&(Some(3), Some(1))),
+        Some((Some(3), None))
+    );
+    assert_eq!(data.apply_change(&(None, None)), None);
+    assert_eq!((data.0 .0, data.1 .0), (8, 2));
+
+    assert_eq!(data.to_add_change(), Some((Some(8), Some(1))));
+    assert_eq!(data.to_remove_change(), Some((Some(-8), Some(-1))));
+
+    assert_eq!(data.apply_change(&(None, Some(-2))), Some((None, Some(-1))));
+    assert_eq!(data.to_add_change(), Some((Some(8), None)));
+
+    let mut change = (Some(1), None);
+    <(Sum, NonEmpty)>::merge_change(&mut change, (Some(2), Some(3)));
+    <(Sum, NonEmpty)>::merge_change(&mut change, (None, Some(-1)));
+    assert_eq!(change, (Some(3), Some(2)));
+}
+
+#[test]
+fn bounded_count_map() {
+    fn entries(entries: &[(u32, i32)]) -> BoundedCountChange<u32> {
+        BoundedCountChange::Entries(entries.iter().copied().collect())
+    }
+
+    // The limits of two aggregation levels, the lower one keeps less details
+    const LOWER_LIMIT: usize = 2;
+    const UPPER_LIMIT: usize = 10;
+    let apply = |lower: &mut BoundedCountMap<u32>,
+                 upper: &mut BoundedCountMap<u32>,
+                 change: &BoundedCountChange<u32>| {
+        if let Some(change) = lower.apply_change(LOWER_LIMIT, change) {
+            upper.apply_change(UPPER_LIMIT, &change);
+        }
+    };
+
+    let mut lower = BoundedCountMap::default();
+    let mut upper = BoundedCountMap::default();
+
+    apply(&mut lower, &mut upper, &entries(&[(1, 1)]));
+    apply(&mut lower, &mut upper, &entries(&[(2, 1), (1, 1)]));
+    assert!(!lower.is_summary());
+    assert_eq!(lower.get(&1), Some(2));
+
+    // Exceeding the limit switches to a summary, but the upper keeps the
+    // details since it has a higher limit
+    apply(&mut lower, &mut upper, &entries(&[(3, 1)]));
+    assert!(lower.is_summary());
+    assert_eq!(lower.get(&1), None);
+    assert_eq!(lower.total(), 4);
+    assert_eq!(upper.get(&3), Some(1));
+    assert_eq!(
+        lower.to_remove_change(),
+        Some(BoundedCountChange::Total(-4))
+    );
+
+    // A summary can only be removed as total, which summarizes the upper
+    let remove = lower.to_remove_change().unwrap();
+    upper.apply_change(UPPER_LIMIT, &remove);
+    let add = lower.to_add_change().unwrap();
+    upper.apply_change(UPPER_LIMIT, &add);
+    assert!(upper.is_summary());
+    assert_eq!(upper.total(), 4);
+
+    // Once empty, the details are tracked again
+    apply(
+        &mut lower,
+        &mut upper,
+        &entries(&[(1, -2), (2, -1), (3, -1)]),
+    );
+    assert!(!lower.is_summary());
+    assert!(!upper.is_summary());
+    assert_eq!(lower.to_add_change(), None);
+    apply(&mut lower, &mut upper, &entries(&[(5, 1)]));
+    assert_eq!(upper.get(&5), Some(1));
+
+    // A negative count which balances out a positive one keeps the summary,
+    // since the details would be wrong after switching back
+    let mut map = BoundedCountMap::default();
+    map.apply_change(LOWER_LIMIT, &entries(&[(1, -1), (2, 1), (3, 1)]));
+    assert!(map.is_summary());
+    assert_eq!(map.total(), 1);
+    map.apply_change(LOWER_LIMIT, &entries(&[(3, -1)]));
+    assert_eq!(map.total(), 0);
+    assert!(map.is_summary());
+    map.apply_change(LOWER_LIMIT, &entries(&[(1, 1), (2, -1)]));
+    assert_eq!(map.total(), 0);
+    assert!(map.is_summary());
+    assert_eq!(map.to_add_change(), Some(BoundedCountChange::Total(0)));
+}
+
+#[test]
+fn detail_limit_summarizes_aggregated_info() {
+    let mut builder = GraphBuilder::new().node("root", 0).detail_limit(3);
+    for i in 0..5 {
+        builder = builder
+            .node(format!("child{i}"), 1)
+            .edge("root", format!("child{i}"));
+    }
+    let graph = builder.build_with_data::<BoundedCountMap<TestNodeRef>>();
+    let child0 = graph.node_ref("child0");
+    graph.aggregated("root", |info| {
+        assert!(info.is_summary());
+        assert_eq!(info.total(), 5);
+        assert_eq!(info.get(&child0), None);
+    });
+
+    // Once the summary is empty again, the details are tracked again
+    for i in 0..5 {
+        graph.change(&format!("child{i}"), -1);
+    }
+    graph.change("child0", 1);
+    graph.aggregated("root", |info| {
+        assert!(!info.is_summary());
+        assert_eq!(info.get(&child0), Some(1));
+    });
+}
+
+#[test]
+fn prepared_change_on_other_thread() {
+    fn assert_send<T: Send>(_: &T) {}
+
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 3);
+
+    let prepared = graph.prepare_change("leaf", 10);
+    assert!(prepared.has_single_upper());
+    assert_send(&prepared);
+    std::thread::scope(|scope| {
+        scope.spawn(|| prepared.apply(graph.context()));
+    });
+    assert_eq!(graph.aggregated_value("root"), 13);
+}
+
+#[cfg(feature = "aggregation_barrier")]
+#[test]
+fn change_barrier_waits_for_prepared_changes() {
+    use super::{barrier::has_pending_changes, change_barrier};
+
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .node("unrelated", 4)
+        .edge("root", "leaf")
+        .build();
+    let root = graph.node_ref("root");
+    let unrelated = graph.node_ref("unrelated");
+    assert_eq!(graph.aggregated_value("root"), 3);
+
+    let prepared = graph.prepare_change("leaf", 10);
+    // The change is pending in the subgraph of the root only, so a barrier on
+    // an unrelated node doesn't wait for it
+    assert!(has_pending_changes(graph.context(), &root));
+    assert!(!has_pending_changes(graph.context(), &unrelated));
+    change_barrier(graph.context(), &unrelated);
+
+    std::thread::scope(|scope| {
+        let graph = &graph;
+        let waiter = scope.spawn(move || {
+            change_barrier(graph.context(), &root);
+            graph.aggregated_value("root")
+        });
+        prepared.apply(graph.context());
+        assert_eq!(waiter.join().unwrap(), 13);
+    });
+    assert!(!has_pending_changes(graph.context(), &root));
+}
+
+#[test]
+fn prepared_change_with_modified_uppers() {
+    let graph = GraphBuilder::new()
+        .node("root1", 100)
+        .node("root2", 200)
+        .node("leaf", 1)
+        .edge("root1", "leaf")
+        .edge("root2", "leaf")
+        .build();
+    assert_eq!(graph.aggregated_value("root1"), 101);
+    assert_eq!(graph.aggregated_value("root2"), 201);
+    assert_eq!(graph.upper_count("leaf"), 2);
+
+    let prepared = graph.prepare_change("leaf", 10);
+    assert!(!prepared.has_single_upper());
+    // The uppers change before the prepared change is applied. The prepared
+    // change still applies to the uppers at the time it was prepared.
+    graph.disconnect("root2", "leaf");
+    assert_eq!(graph.upper_count("leaf"), 1);
+    prepared.apply(graph.context());
+
+    assert_eq!(graph.aggregated_value("root1"), 111);
+    assert_eq!(graph.aggregated_value("root2"), 200);
+}
+
+#[test]
+fn prepared_change_across_promotion() {
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 3);
+    assert_eq!(graph.upper_kind("leaf"), UpperKind::Inner);
+    assert_eq!(graph.max_bottom_tree_height("leaf"), None);
+
+    let prepared = graph.prepare_change("leaf", 10);
+    // Aggregating the leaf itself makes it the left child of its own bottom
+    // tree before the prepared change is applied. The current info, which
+    // already includes the change, is moved from the old uppers to the new
+    // one.
+    assert_eq!(graph.aggregated_value("leaf"), 11);
+    assert_eq!(graph.upper_kind("leaf"), UpperKind::Left);
+    // The top tree of the root aggregates the left child through a top tree of
+    // it at depth 1, which uses a bottom tree of height 5.
+    assert_eq!(graph.max_bottom_tree_height("leaf"), Some(5));
+    // The root only misses the pending change
+    assert_eq!(graph.aggregated_value("root"), 3);
+    prepared.apply(graph.context());
+
+    assert_eq!(graph.aggregated_value("root"), 13);
+    assert_eq!(graph.aggregated_value("leaf"), 11);
+
+    // Changes prepared after the promotion use the new upper.
+    graph.prepare_change("leaf", 100).apply(graph.context());
+    assert_eq!(graph.aggregated_value("root"), 113);
+    assert_eq!(graph.aggregated_value("leaf"), 111);
+}
+
+#[test]
+fn pending_change_defers_promotion() {
+    let graph = GraphBuilder::new()
+        .promotion_thresholds(PromotionThresholds {
+            half_life: Duration::from_secs(3600),
+            hot_change_frequency: 3.0,
+            hot_children_inner_threshold: 0,
+            cold_change_frequency: 0.0,
+        })
+        .node("root0", 100)
+        .node("root1", 200)
+        .node("middle", 10)
+        .node("child0", 0)
+        .node("child1", 1)
+        .node("child2", 2)
+        .edge("middle", "child0")
+        .edge("root0", "middle")
+        .edge("root1", "middle")
+        .build();
+    assert_eq!(graph.aggregated_value("root0"), 110);
+    assert_eq!(graph.aggregated_value("root1"), 210);
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Inner);
+    for _ in 0..2 {
+        graph.change("middle", 1);
+        graph.change("middle", -1);
+    }
+
+    // Adding a child to the hot leaf would promote it, but not while one of its
+    // changes is pending
+    let prepared = graph.prepare_change("middle", 1000);
+    graph.connect("middle", "child1");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Inner);
+    prepared.apply(graph.context());
+    assert_eq!(graph.aggregated_value("root0"), 1111);
+    assert_eq!(graph.aggregated_value("root1"), 1211);
+
+    // Once the change is applied, the next child promotes it
+    graph.connect("middle", "child2");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Left);
+    assert_eq!(graph.aggregated_value("root0"), 1113);
+    assert_eq!(graph.aggregated_value("root1"), 1213);
+}
+
+#[cfg(feature = "aggregation_async")]
+#[tokio::test]
+async fn apply_change_async() {
+    use super::async_apply::apply_change_async;
+
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 3);
+
+    apply_change_async(graph.context(), &graph.node_ref("leaf"), |item| {
+        item.add_value(10);
+        Some(10)
+    })
+    .await;
+    assert_eq!(graph.value("leaf"), 11);
+    assert_eq!(graph.aggregated_value("root"), 13);
+
+    apply_change_async(graph.context(), &graph.node_ref("leaf"), |_| None).await;
+    assert_eq!(graph.aggregated_value("root"), 13);
+}
+
+#[cfg(feature = "aggregation_contention")]
+#[test]
+fn contention_stats() {
+    use super::take_contention_stats;
+
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build_with(RecordingContext::new);
+    let ctx = graph.context();
+    assert_eq!(graph.aggregated_value("root"), 3);
+
+    take_contention_stats();
+    ctx.take_operations();
+    graph.change("leaf", 10000);
+    let applied = ctx
+        .take_operations()
+        .into_iter()
+        .filter(|op| matches!(op, AggregationOperation::ApplyChange { .. }))
+        .count();
+    let stats = take_contention_stats();
+    // Other tests might run concurrently
+    assert!(stats.waves >= 1);
+    assert!(stats.locks >= applied as u64);
+    assert_eq!(stats.histogram.iter().sum::<u64>(), stats.locks);
+}
+
+#[cfg(feature = "aggregation_tracing")]
+#[test]
+fn propagation_trace_spans() {
+    use std::collections::HashMap;
+
+    use tracing::{
+        field::{Field, Visit},
+        span::{Attributes, Id, Record},
+        Event, Metadata, Subscriber,
+    };
+
+    #[derive(Default)]
+    struct Fields(HashMap<String, u64>);
+
+    impl Visit for Fields {
+        fn record_u64(&mut self, field: &Field, value: u64) {
+            self.0.insert(field.name().to_string(), value);
+        }
+
+        fn record_debug(&mut self, _field: &Field, _value: &dyn std::fmt::Debug) {}
+    }
+
+    #[derive(Default, Clone)]
+    struct WaveSubscriber {
+        waves: Arc<Mutex<Vec<Fields>>>,
+    }
+
+    impl Subscriber for WaveSubscriber {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut waves = self.waves.lock();
+            let mut fields = Fields::default();
+            if span.metadata().name() == "aggregation_tree::propagate" {
+                span.record(&mut fields);
+            }
+            waves.push(fields);
+            Id::from_u64(waves.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            values.record(&mut self.waves.lock()[span.into_u64() as usize - 1]);
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    let graph = GraphBuilder::new()
+        .node("root", 1)
+        .node("a", 2)
+        .node("b", 4)
+        .edge("root", "a")
+        .edge("a", "b")
+        .build();
+    assert_eq!(graph.aggregated_value("root"), 7);
+
+    let subscriber = WaveSubscriber::default();
+    tracing::subscriber::with_default(subscriber.clone(), || {
+        graph.change("b", 1);
+        graph.change("a", 1);
+    });
+    assert_eq!(graph.aggregated_value("root"), 9);
+
+    let waves = subscriber.waves.lock();
+    let waves = waves
+        .iter()
+        .filter(|fields| fields.0.contains_key("wave"))
+        .collect::<Vec<_>>();
+    assert_eq!(waves.len(), 2);
+    assert!(waves[0].0["wave"] < waves[1].0["wave"]);
+    for wave in waves {
+        assert!(wave.0["nodes"] >= 1);
+        assert_eq!(wave.0["origin_level"], 0);
+    }
+}
+
+#[test]
+fn graph_builder_chains() {
+    let graph = GraphBuilder::new()
+        .leaf("a")
+        .upper("b")
+        .upper("c")
+        .leaf("d")
+        .upper("b")
+        .aggregating("c")
+        .build();
+    assert_eq!(graph.max_top_tree_depth("c"), Some(0));
+    assert_eq!(graph.max_top_tree_depth("b"), None);
+    assert_eq!(graph.aggregated_value("c"), 2);
+    assert_eq!(graph.aggregated_value("b"), 2);
+    assert_eq!(graph.value("b"), 0);
+
+    graph.change("d", 10);
+    assert_eq!(graph.aggregated_value("c"), 12);
+}
+
+#[test]
+fn leaf_introspection() {
+    let graph = GraphBuilder::new()
+        .node("root", 2)
+        .node("leaf", 1)
+        .edge("root", "leaf")
+        .build();
+    assert_eq!(graph.upper_kind("root"), UpperKind::Inner);
+    assert_eq!(graph.upper_count("root"), 0);
+    assert_eq!(graph.max_bottom_tree_height("root"), None);
+    assert_eq!(graph.max_top_tree_depth("root"), None);
+    assert!(graph.upper_heights("root").is_empty());
+
+    assert_eq!(graph.aggregated_value("root"), 3);
+
+    assert_eq!(graph.upper_kind("root"), UpperKind::Left);
+    assert_eq!(graph.upper_count("root"), 1);
+    assert_eq!(graph.upper_heights("root"), vec![0]);
+    assert_eq!(graph.max_bottom_tree_height("root"), Some(4));
+    assert_eq!(graph.max_top_tree_depth("root"), Some(0));
+    assert_eq!(
+        aggregation_info(graph.context(), &graph.node_ref("root")).depth(),
+        0
+    );
+    assert_eq!(graph.upper_kind("leaf"), UpperKind::Inner);
+    assert_eq!(graph.upper_count("leaf"), 1);
+    assert_eq!(graph.upper_heights("leaf"), vec![0]);
+    assert_eq!(graph.max_bottom_tree_height("leaf"), None);
+    assert_eq!(graph.max_top_tree_depth("leaf"), None);
+}
+
+#[test]
+fn adaptive_promotion() {
+    fn run(hot: bool) -> UpperKind {
+        let graph = GraphBuilder::new()
+            .promotion_thresholds(PromotionThresholds {
+                half_life: Duration::from_secs(3600),
+                hot_change_frequency: 3.0,
+                hot_children_inner_threshold: 0,
+                cold_change_frequency: 0.0,
+            })
+            .node("root0", 100)
+            .node("root1", 200)
+            .node("middle", 10)
+            .node("child0", 0)
+            .node("child1", 1)
+            .node("child2", 2)
+            .edge("middle", "child0")
+            .edge("middle", "child1")
+            .edge("middle", "child2")
+            .edge("root0", "middle")
+            .edge("root1", "middle")
+            .build();
+        if hot {
+            for _ in 0..2 {
+                graph.change("middle", 1);
+                graph.change("middle", -1);
+            }
+        }
+
+        // The second root adds another upper to the middle node, which
+        // exceeds the threshold for hot leaves
+        assert_eq!(graph.aggregated_value("root0"), 113);
+        assert_eq!(graph.aggregated_value("root1"), 213);
+
+        graph.upper_kind("middle")
+    }
+
+    assert_eq!(run(false), UpperKind::Inner);
+    assert_eq!(run(true), UpperKind::Left);
+}
+
+#[test]
+fn cold_leaf_demotion() {
+    let graph = GraphBuilder::new()
+        .promotion_thresholds(PromotionThresholds {
+            half_life: Duration::from_millis(100),
+            hot_change_frequency: 2.0,
+            hot_children_inner_threshold: 0,
+            cold_change_frequency: 0.5,
+        })
+        .node("root0", 100)
+        .node("root1", 200)
+        .node("middle", 10)
+        .node("child0", 0)
+        .node("child1", 1)
+        .node("child2", 2)
+        .node("new_child", 1000)
+        .node("another_child", 2000)
+        .edge("middle", "child0")
+        .edge("middle", "child1")
+        .edge("middle", "child2")
+        .edge("root0", "middle")
+        .edge("root1", "middle")
+        .build();
+    for _ in 0..5 {
+        graph.change("middle", 1);
+        graph.change("middle", -1);
+    }
+    assert_eq!(graph.aggregated_value("root0"), 113);
+    assert_eq!(graph.aggregated_value("root1"), 213);
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Left);
+
+    // The hot leaf keeps its bottom tree, even when it's unconnected
+    graph.disconnect("root0", "middle");
+    graph.disconnect("root1", "middle");
+    assert_eq!(graph.aggregated_value("root0"), 100);
+    assert_eq!(graph.aggregated_value("root1"), 200);
+    graph.connect("middle", "new_child");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Left);
+
+    // Still hot after a single half life
+    graph.advance_time(Duration::from_millis(100));
+    graph.disconnect("middle", "new_child");
+    graph.connect("middle", "new_child");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Left);
+
+    // A pending change references the bottom tree and could connect it to
+    // another upper, so it's kept even when the leaf is cold
+    let prepared = graph.prepare_change("middle", 0);
+    assert!(prepared.has_single_upper());
+    graph.advance_time(Duration::from_millis(1400));
+    graph.disconnect("middle", "new_child");
+    graph.connect("middle", "new_child");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Left);
+    prepared.apply(graph.context());
+
+    // Once nothing else references it, the next reorganization releases the
+    // bottom tree
+    graph.connect("middle", "another_child");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Inner);
+    assert_eq!(graph.upper_count("middle"), 0);
+
+    graph.connect("root0", "middle");
+    assert_eq!(graph.upper_kind("middle"), UpperKind::Inner);
+    assert_eq!(graph.aggregated_value("root0"), 3113);
+    assert_eq!(graph.aggregated_value("root1"), 200);
+    assert_eq!(graph.aggregated_value("middle"), 3013);
+}
+
+#[test]
+fn demotion_hysteresis() {
+    let thresholds = PromotionThresholds {
+        half_life: Duration::from_millis(100),
+        hot_change_frequency: 2.0,
+        hot_children_inner_threshold: 0,
+        cold_change_frequency: 0.5,
+    };
+    let start = Instant::now();
+    let mut frequency = ChangeFrequency::default();
+    assert!(frequency.is_cold(start, thresholds));
+
+    // A promoted leaf is not demoted within the same half life
+    frequency.record_promotion(start);
+    assert!(!frequency.is_cold(start, thresholds));
+    assert!(!frequency.is_cold(start + Duration::from_millis(99), thresholds));
+    assert!(frequency.is_cold(start + Duration::from_millis(100), thresholds));
+}
+
+#[test]
+fn concurrent_change_frequency() {
+    let half_life = Duration::from_secs(3600);
+    let now = Instant::now();
+    let frequency = ChangeFrequency::default();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..100 {
+                    frequency.record(now, half_life);
+                }
+            });
+        }
+    });
+    assert_eq!(frequency.get(now, half_life), 400.0);
+    assert_eq!(frequency.get(now + half_life, half_life), 200.0);
+}
+
+#[test]
+fn chaos_concurrent_changes() {
+    let mut builder = GraphBuilder::new().node("root", 0);
+    for middle in 0..4 {
+        builder = builder
+            .node(format!("middle{middle}"), 0)
+            .edge("root", format!("middle{middle}"));
+        for leaf in middle * 4..middle * 4 + 4 {
+            builder = builder
+                .node(format!("leaf{leaf}"), 0)
+                .edge(format!("middle{middle}"), format!("leaf{leaf}"));
+        }
+    }
+    let graph = builder.build_with(|inner| ChaosContext::new(inner, 42, Duration::from_micros(50)));
+    assert_eq!(graph.aggregated_value("root"), 0);
+
+    std::thread::scope(|scope| {
+        for middle in 0..4 {
+            let graph = &graph;
+            scope.spawn(move || {
+                for j in 0..25 {
+                    for leaf in middle * 4..middle * 4 + 4 {
+                        let delta = (middle + j % 3) as i32;
+                        let job = graph
+                            .context()
+                            .wrap_job(graph.change_job(&format!("leaf{leaf}"), delta));
+                        job();
+                    }
+                }
+            });
+        }
+    });
+
+    let expected = (0..16)
+        .map(|leaf| graph.value(&format!("leaf{leaf}")))
+        .sum::<i32>();
+    assert!(expected > 0);
+    assert_eq!(graph.aggregated_value("root"), expected);
+}
+
+#[test]
+fn concurrent_changes_and_uppers() {
+    let mut builder = GraphBuilder::new().node("root", 0);
+    for middle in 0..4 {
+        builder = builder
+            .node(format!("middle{middle}"), 0)
+            .edge("root", format!("middle{middle}"));
+        for leaf in middle * 4..middle * 4 + 4 {
+            builder = builder
+                .node(format!("leaf{leaf}"), 0)
+                .edge(format!("middle{middle}"), format!("leaf{leaf}"));
+        }
+    }
+    let graph = builder.build();
+    assert_eq!(graph.aggregated_value("root"), 0);
+
+    std::thread::scope(|scope| {
+        for middle in 0..4 {
+            let graph = &graph;
+            scope.spawn(move || {
+                for _ in 0..25 {
+                    for leaf in middle * 4..middle * 4 + 4 {
+                        graph.change(&format!("leaf{leaf}"), 1);
+                    }
+                }
+            });
+        }
+        // Modify the uppers of the changing leaves at the same time
+        let graph = &graph;
+        scope.spawn(move || {
+            for i in 0..50 {
+                let middle = format!("middle{}", i % 4);
+                let leaf = format!("leaf{}", (i * 7) % 16);
+                graph.connect(&middle, &leaf);
+                graph.disconnect(&middle, &leaf);
+            }
+        });
+    });
+    assert_eq!(graph.aggregated_value("root"), 400);
+}
+
+/// Counts how often a [Transitions] count went negative.
+static NEGATIVE_TRANSITIONS: AtomicU32 = AtomicU32::new(0);
+
+/// Aggregates like the backend aggregates unfinished tasks: Only transitions
+/// between "something unfinished" and "nothing unfinished" are propagated.
+/// Such changes depend on the order they are applied in.
+#[derive(Default, Clone)]
+struct Transitions(i32);
+
+impl AggregatedData for Transitions {
+    type Change = i32;
+
+    fn merge_change(change: &mut i32, other: i32) {
+        *change += other;
+    }
+
+    fn apply_change(&mut self, change: &i32) -> Option<i32> {
+        let was_unfinished = self.0 > 0;
+        self.0 += change;
+        if self.0 < 0 {
+            NEGATIVE_TRANSITIONS.fetch_add(1, Ordering::SeqCst);
+        }
+        match (was_unfinished, self.0 > 0) {
+            (false, true) => Some(1),
+            (true, false) => Some(-1),
+            _ => None,
+        }
+    }
+
+    fn to_add_change(&self) -> Option<i32> {
+        (self.0 > 0).then_some(1)
+    }
+
+    fn to_remove_change(&self) -> Option<i32> {
+        (self.0 > 0).then_some(-1)
+    }
+}
+
+impl TestData for Transitions {
+    fn value_change(_node: TestNodeRef, delta: i32) -> i32 {
+        delta
+    }
+}
+
+#[test]
+fn concurrent_transition_changes() {
+    let mut builder = GraphBuilder::new()
+        .node("root", 0)
+        .node("pinned", 1)
+        .edge("root", "pinned");
+    for middle in 0..4 {
+        builder = builder
+            .node(format!("middle{middle}"), 0)
+            .edge("root", format!("middle{middle}"));
+        for leaf in middle * 4..middle * 4 + 4 {
+            builder = builder
+                .node(format!("leaf{leaf}"), 0)
+                .edge(format!("middle{middle}"), format!("leaf{leaf}"));
+        }
+    }
+    let graph = builder.build_with(|inner: TestAggregationContext<Transitions>| {
+        ChaosContext::new(inner, 42, Duration::from_micros(50))
+    });
+    let unfinished = |name: &str| graph.aggregated(name, |info| info.0);
+    assert_eq!(unfinished("root"), 1);
+
+    let done = AtomicBool::new(false);
+    std::thread::scope(|scope| {
+        let workers = (0..4)
+            .map(|thread| {
+                let graph = &graph;
+                // Every thread toggles its own leaves, so the changes of a leaf
+                // are ordered, but the aggregated nodes receive them
+                // concurrently.
+                scope.spawn(move || {
+                    for i in 0..100 {
+                        let leaf = format!("leaf{}", thread + (i % 4) * 4);
+                        graph.change(&leaf, 1);
+                        graph.change(&leaf, -1);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        // The pinned node is always unfinished, so the root must never be
+        // seen as finished.
+        let graph = &graph;
+        let done = &done;
+        scope.spawn(move || {
+            while !done.load(Ordering::SeqCst) {
+                assert_eq!(graph.aggregated("root", |info| info.0), 1);
+            }
+        });
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        done.store(true, Ordering::SeqCst);
+    });
+    // An aggregated node only goes negative when it receives the end of a
+    // transition before its start.
+    assert_eq!(NEGATIVE_TRANSITIONS.load(Ordering::SeqCst), 0);
+    assert_eq!(unfinished("root"), 1);
+    for middle in 0..4 {
+        assert_eq!(unfinished(&format!("middle{middle}")), 0);
+    }
+}
+
+#[test]
+fn custom_statistics() {
+    // Long enough to create bottom trees and a top tree above the root.
+    let mut builder = GraphBuilder::new();
+    for i in 0..100 {
+        builder = builder.node(format!("node{i}"), 0);
+        if i > 0 {
+            builder = builder.edge(format!("node{}", i - 1), format!("node{i}"));
+        }
+    }
+    let graph = builder.build_with_data::<(i32, Transitions)>();
+    let root = |graph: &TestGraph<_>| graph.aggregated("node0", |(sum, t)| (*sum, t.0));
+    assert_eq!(root(&graph), (0, 0));
+
+    graph.change("node99", 3);
+    assert_eq!(root(&graph), (3, 1));
+    graph.change("node50", 2);
+    assert_eq!(root(&graph), (5, 2));
+    assert_eq!(graph.aggregated("node60", |(sum, t)| (*sum, t.0)), (3, 1));
+
+    graph.change("node99", -3);
+    assert_eq!(root(&graph), (2, 1));
+    graph.change("node50", -2);
+    assert_eq!(root(&graph), (0, 0));
+}
+
+#[test]
+fn graph_builder() {
+    let graph = GraphBuilder::new()
+        .node("a", 1)
+        .node("b", 2)
+        .node("c", 4)
+        .node("d", 8)
+        .edge("a", "b")
+        .edge("a", "c")
+        .edge("b", "d")
+        .build();
+    assert_eq!(graph.aggregated_value("a"), 1 + 2 + 4 + 8);
+    assert_eq!(graph.aggregated_value("b"), 2 + 8);
+
+    graph.change("d", 100);
+    assert_eq!(graph.value("d"), 108);
+    assert_eq!(graph.aggregated_value("a"), 1 + 2 + 4 + 108);
+    assert_eq!(graph.aggregated_value("b"), 2 + 108);
+
+    graph.disconnect("b", "d");
+    assert_eq!(graph.aggregated_value("a"), 1 + 2 + 4);
+    assert_eq!(graph.aggregated_value("b"), 2);
+
+    graph.connect("c", "d");
+    assert_eq!(graph.aggregated_value("a"), 1 + 2 + 4 + 108);
+    assert_eq!(graph.aggregated_value("c"), 4 + 108);
+}
+
+#[test]
+fn cow_info() {
+    let mut info = CowInfo::<Aggregated>::default();
+    info.make_mut().value = 1;
+    let snapshot = info.clone();
+    // The snapshot is alive, so this clones
+    let ptr = &*info as *const Aggregated;
+    info.make_mut().value = 2;
+    assert_ne!(&*info as *const Aggregated, ptr);
+    assert_eq!(snapshot.value, 1);
+    drop(snapshot);
+    let ptr = &*info as *const Aggregated;
+    info.make_mut().value = 3;
+    assert_eq!(&*info as *const Aggregated, ptr);
+    assert_eq!(info.value, 3);
+}
+
 const RECT_SIZE: usize = 100;
 const RECT_MULT: usize = 100;
 
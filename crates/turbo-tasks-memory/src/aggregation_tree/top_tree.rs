@@ -3,7 +3,15 @@ use std::{mem::transmute, ops::ControlFlow, sync::Arc};
 use parking_lot::{Mutex, MutexGuard};
 use ref_cast::RefCast;
 
-use super::{inner_refs::TopRef, leaf::top_tree, AggregationContext};
+use super::{
+    contention,
+    inner_refs::TopRef,
+    leaf::top_tree,
+    locked_node::{AggregatedNodeId, LockedNode},
+    propagation_trace,
+    reentrancy::{self, PropagationGuard},
+    AggregationContext,
+};
 use crate::count_hash_set::CountHashSet;
 
 /// The top half of the aggregation tree. It can aggregate all nodes of a
@@ -18,7 +26,11 @@ pub struct TopTree<T> {
 
 struct TopTreeState<T> {
     data: T,
-    upper: CountHashSet<TopRef<T>>,
+    /// Incremented on every change applied to `data` and on every mutable
+    /// access through [AggregationInfoGuard].
+    generation: u64,
+    /// Copy-on-write, so a change can capture the uppers cheaply.
+    upper: Arc<CountHashSet<TopRef<T>>>,
 }
 
 impl<T: Default> TopTree<T> {
@@ -27,13 +39,28 @@ impl<T: Default> TopTree<T> {
             depth,
             state: Mutex::new(TopTreeState {
                 data: T::default(),
-                upper: CountHashSet::new(),
+                generation: 0,
+                upper: Arc::new(CountHashSet::new()),
             }),
         }
     }
 }
 
 impl<T> TopTree<T> {
+    /// Locks the state and reports it to the context.
+    fn lock<'c, C: AggregationContext<Info = T>>(
+        &self,
+        aggregation_context: &'c C,
+    ) -> LockedNode<'c, C, MutexGuard<'_, TopTreeState<T>>> {
+        reentrancy::assert_not_applying_change();
+        let state = self.state.lock();
+        LockedNode::new(
+            aggregation_context,
+            AggregatedNodeId::of(&state.data),
+            state,
+        )
+    }
+
     pub fn add_children_of_child<'a, C: AggregationContext<Info = T>>(
         self: &Arc<Self>,
         aggregation_context: &C,
@@ -83,8 +110,8 @@ impl<T> TopTree<T> {
         aggregation_context: &C,
         upper: &Arc<TopTree<T>>,
     ) {
-        let mut state = self.state.lock();
-        if state.upper.add_clonable(TopRef::ref_cast(upper)) {
+        let mut state = self.lock(aggregation_context);
+        if Arc::make_mut(&mut state.upper).add_clonable(TopRef::ref_cast(upper)) {
             if let Some(change) = aggregation_context.info_to_add_change(&state.data) {
                 upper.child_change(aggregation_context, &change);
             }
@@ -96,8 +123,8 @@ impl<T> TopTree<T> {
         aggregation_context: &C,
         upper: &Arc<TopTree<T>>,
     ) {
-        let mut state = self.state.lock();
-        if state.upper.remove_clonable(TopRef::ref_cast(upper)) {
+        let mut state = self.lock(aggregation_context);
+        if Arc::make_mut(&mut state.upper).remove_clonable(TopRef::ref_cast(upper)) {
             if let Some(change) = aggregation_context.info_to_remove_change(&state.data) {
                 upper.child_change(aggregation_context, &change);
             }
@@ -109,9 +136,17 @@ impl<T> TopTree<T> {
         aggregation_context: &C,
         change: &C::ItemChange,
     ) {
-        let mut state = self.state.lock();
-        let change = aggregation_context.apply_change(&mut state.data, change);
-        propagate_change_to_upper(&state, aggregation_context, change);
+        let _guard = PropagationGuard::enter();
+        propagation_trace::visit(self, "top_tree", self.depth);
+        let state = contention::lock_timed(|| self.state.lock());
+        let mut state = LockedNode::new(
+            aggregation_context,
+            AggregatedNodeId::of(&state.data),
+            state,
+        );
+        let change = reentrancy::apply_change(aggregation_context, &mut state.data, change);
+        state.generation += 1;
+        propagate_change_to_upper(state, aggregation_context, change);
     }
 
     pub fn get_root_info<C: AggregationContext<Info = T>>(
@@ -119,7 +154,7 @@ impl<T> TopTree<T> {
         aggregation_context: &C,
         root_info_type: &C::RootInfoType,
     ) -> C::RootInfo {
-        let state = self.state.lock();
+        let state = self.lock(aggregation_context);
         if self.depth == 0 {
             // This is the root
             aggregation_context.info_to_root_info(&state.data, root_info_type)
@@ -136,18 +171,27 @@ impl<T> TopTree<T> {
         }
     }
 
+    pub fn with_info<R>(&self, func: impl FnOnce(&T) -> R) -> R {
+        reentrancy::assert_not_applying_change();
+        func(&self.state.lock().data)
+    }
+
     pub fn lock_info(self: &Arc<Self>) -> AggregationInfoGuard<T> {
+        reentrancy::assert_not_applying_change();
         AggregationInfoGuard {
             // SAFETY: We can cast the lifetime as we keep a strong reference to the tree.
             // The order of the field in the struct is important to drop guard before tree.
             guard: unsafe { transmute(self.state.lock()) },
             tree: self.clone(),
+            mutated: false,
         }
     }
 }
 
+/// Propagates a change to the uppers. Like for bottom trees the lock is held
+/// until the change has been propagated.
 fn propagate_change_to_upper<C: AggregationContext>(
-    state: &MutexGuard<TopTreeState<C::Info>>,
+    state: LockedNode<'_, C, MutexGuard<'_, TopTreeState<C::Info>>>,
     aggregation_context: &C,
     change: Option<C::ItemChange>,
 ) {
@@ -163,6 +207,18 @@ pub struct AggregationInfoGuard<T: 'static> {
     guard: MutexGuard<'static, TopTreeState<T>>,
     #[allow(dead_code, reason = "need to stay alive until the guard is dropped")]
     tree: Arc<TopTree<T>>,
+    /// The generation has already been incremented for this guard.
+    mutated: bool,
+}
+
+impl<T> AggregationInfoGuard<T> {
+    /// Returns the generation of the info. It's incremented on every change
+    /// that is applied to the info and once per guard that gives mutable
+    /// access to it. Comparing generations allows to detect if the info has
+    /// been changed between two reads.
+    pub fn generation(&self) -> u64 {
+        self.guard.generation
+    }
 }
 
 impl<T> std::ops::Deref for AggregationInfoGuard<T> {
@@ -175,6 +231,10 @@ impl<T> std::ops::Deref for AggregationInfoGuard<T> {
 
 impl<T> std::ops::DerefMut for AggregationInfoGuard<T> {
     fn deref_mut(&mut self) -> &mut Self::Target {
+        if !self.mutated {
+            self.mutated = true;
+            self.guard.generation += 1;
+        }
         &mut self.guard.data
     }
 }
@@ -7,6 +7,7 @@
 #![feature(impl_trait_in_assoc_type)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
+mod aggregation_dump;
 mod aggregation_tree;
 mod cell;
 mod concurrent_priority_queue;
@@ -20,5 +21,20 @@ pub mod stats;
 mod task;
 pub mod viz;
 
+#[cfg(feature = "aggregation_barrier")]
+pub use aggregation_tree::change_barrier;
+#[cfg(feature = "test-util")]
+pub use aggregation_tree::test_util;
+#[cfg(feature = "aggregation_async")]
+pub use aggregation_tree::{apply_change_async, AsyncAggregationContext};
+pub use aggregation_tree::{
+    assert_not_applying_change, AggregatedData, AggregatedNodeId, AggregationContext,
+    AggregationItemLock, AggregationTreeLeaf, BoundedCountChange, BoundedCountMap, ChangeJobQueue,
+    UpperKind,
+};
+#[cfg(feature = "aggregation_contention")]
+pub use aggregation_tree::{
+    contention_stats, take_contention_stats, ContentionStats, HISTOGRAM_BUCKETS,
+};
 pub use memory_backend::MemoryBackend;
 pub use memory_backend_with_pg::MemoryBackendWithPersistedGraph;
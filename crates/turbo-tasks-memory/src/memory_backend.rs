@@ -2,8 +2,10 @@ use std::{
     borrow::{Borrow, Cow},
     cell::RefCell,
     cmp::min,
+    fmt::Write,
     future::Future,
     hash::{BuildHasher, BuildHasherDefault, Hash},
+    path::{Path, PathBuf},
     pin::Pin,
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -29,10 +31,15 @@ use turbo_tasks::{
 };
 
 use crate::{
+    aggregation_dump::{AggregationDumpThread, AGGREGATION_DUMP_ENV},
+    aggregation_tree::{ChangeBatch, CowInfo},
     cell::RecomputingCell,
     gc::GcQueue,
     output::Output,
-    task::{Task, TaskDependency, TaskDependencySet, DEPENDENCIES_TO_TRACK},
+    task::{
+        aggregation::{Aggregated, TaskAggregationContext},
+        Task, TaskDependency, TaskDependencySet, DEPENDENCIES_TO_TRACK,
+    },
 };
 
 pub struct MemoryBackend {
@@ -43,6 +50,7 @@ pub struct MemoryBackend {
     memory_limit: usize,
     gc_queue: Option<GcQueue>,
     idle_gc_active: AtomicBool,
+    aggregation_dump: AggregationDumpThread,
 }
 
 impl Default for MemoryBackend {
@@ -65,6 +73,7 @@ impl MemoryBackend {
             memory_limit,
             gc_queue: (memory_limit != usize::MAX).then(GcQueue::new),
             idle_gc_active: AtomicBool::new(false),
+            aggregation_dump: AggregationDumpThread::default(),
         }
     }
 
@@ -130,6 +139,61 @@ impl MemoryBackend {
         }
     }
 
+    /// Takes a snapshot of the aggregated info of all cached tasks that are the
+    /// root of an aggregation, i. e. whose aggregated info has been requested.
+    /// Only pointers are copied while the tasks are locked. The generation of
+    /// each info allows to detect which ones have changed between two
+    /// snapshots.
+    pub(crate) fn snapshot_aggregation_roots(&self) -> Vec<(TaskId, u64, CowInfo<Aggregated>)> {
+        let mut roots = Vec::new();
+        self.with_all_cached_tasks(|id| {
+            if let Some((generation, info)) = self.with_task(id, |task| task.snapshot_aggregated())
+            {
+                roots.push((id, generation, info));
+            }
+        });
+        roots
+    }
+
+    /// Writes the aggregation structure of all cached tasks to a file. This is
+    /// meant for debugging, e. g. to capture the state of a hanging process.
+    ///
+    /// It can be called while tasks are running. Only one task is locked at a
+    /// time, and the top and bottom trees cached in its leaf are locked while
+    /// the task state is held. This is the same order as in the aggregation
+    /// itself (task state before aggregated nodes), so it can't deadlock. The
+    /// dump is not an atomic snapshot across tasks though.
+    pub fn dump_aggregation_structure(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = String::new();
+        self.with_all_cached_tasks(|id| {
+            self.with_task(id, |task| task.dump_aggregation(&mut out))
+                .expect("writing to a String can't fail");
+        });
+        let roots = self.snapshot_aggregation_roots();
+        writeln!(out, "aggregation roots ({}):", roots.len()).unwrap();
+        for (id, generation, info) in roots {
+            let description = self.with_task(id, |task| task.get_description());
+            writeln!(
+                out,
+                "  {description} (generation {generation}): {}",
+                info.summary()
+            )
+            .unwrap();
+        }
+        #[cfg(feature = "aggregation_contention")]
+        {
+            let stats = crate::contention_stats();
+            writeln!(
+                out,
+                "lock contention: {} waves, {} locks, {:?} total wait, {:?} max wave wait, \
+                 histogram {:?}",
+                stats.waves, stats.locks, stats.total_wait, stats.max_wave_wait, stats.histogram
+            )
+            .unwrap();
+        }
+        std::fs::write(path, out)
+    }
+
     pub fn run_gc(&self, idle: bool, turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>) {
         if let Some(gc_queue) = &self.gc_queue {
             const MAX_COLLECT_FACTOR: u8 = u8::MAX / 8;
@@ -223,15 +287,34 @@ impl MemoryBackend {
         set: TaskIdSet,
         turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>,
     ) {
+        let mut aggregation_context = TaskAggregationContext::new(turbo_tasks, self);
+        let mut batch = ChangeBatch::new();
         for task in set {
             self.with_task(task, |task| {
-                task.schedule_when_dirty_from_aggregation(self, turbo_tasks)
+                if let Some(prepared) =
+                    task.schedule_when_dirty_from_aggregation(&aggregation_context, turbo_tasks)
+                {
+                    batch.add_prepared(&aggregation_context, prepared);
+                }
             });
         }
+        batch.flush(&aggregation_context);
+        aggregation_context.apply_queued_updates();
     }
 }
 
 impl Backend for MemoryBackend {
+    fn startup(&self, turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>) {
+        if let Some(path) = std::env::var_os(AGGREGATION_DUMP_ENV) {
+            self.aggregation_dump
+                .start(PathBuf::from(path), turbo_tasks);
+        }
+    }
+
+    fn stop(&self, _turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>) {
+        self.aggregation_dump.stop();
+    }
+
     fn idle_start(&self, turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>) {
         if self
             .idle_gc_active
@@ -1,4 +1,4 @@
-mod aggregation;
+pub(crate) mod aggregation;
 mod meta_state;
 mod stats;
 
@@ -35,7 +35,9 @@ use turbo_tasks::{
 };
 
 use crate::{
-    aggregation_tree::{aggregation_info, ensure_thresholds, AggregationInfoGuard},
+    aggregation_tree::{
+        aggregation_info, ensure_thresholds, AggregationInfoGuard, CowInfo, PreparedChange,
+    },
     cell::Cell,
     gc::{to_exp_u8, GcPriority, GcStats, GcTaskState},
     output::{Output, OutputContent},
@@ -418,7 +420,9 @@ enum TaskStateType {
 use TaskStateType::*;
 
 use self::{
-    aggregation::{Aggregated, RootInfoType, RootType, TaskAggregationTreeLeaf, TaskGuard},
+    aggregation::{
+        Aggregated, AggregatedInfo, RootInfoType, RootType, TaskAggregationTreeLeaf, TaskGuard,
+    },
     meta_state::{
         FullTaskWriteGuard, TaskMetaState, TaskMetaStateReadGuard, TaskMetaStateWriteGuard,
     },
@@ -519,10 +523,10 @@ impl Task {
 
     fn set_root_type(
         aggregation_context: &TaskAggregationContext,
-        aggregation: &mut AggregationInfoGuard<Aggregated>,
+        aggregation: &mut AggregationInfoGuard<AggregatedInfo>,
         root_type: RootType,
     ) {
-        aggregation.root_type = Some(root_type);
+        aggregation.make_mut().root_type = Some(root_type);
         let dirty_tasks = aggregation
             .dirty_tasks
             .iter()
@@ -540,7 +544,11 @@ impl Task {
     ) {
         let mut aggregation_context = TaskAggregationContext::new(turbo_tasks, backend);
         {
-            aggregation_context.aggregation_info(id).lock().root_type = None;
+            aggregation_context
+                .aggregation_info(id)
+                .lock()
+                .make_mut()
+                .root_type = None;
         }
         aggregation_context.apply_queued_updates();
     }
@@ -562,6 +570,48 @@ impl Task {
         None
     }
 
+    /// Returns a snapshot of the aggregated info of this task and its
+    /// generation, when it has been requested before. It only copies a
+    /// pointer.
+    pub(crate) fn snapshot_aggregated(&self) -> Option<(u64, CowInfo<Aggregated>)> {
+        match self.state() {
+            TaskMetaStateReadGuard::Full(state) => {
+                state.aggregation_leaf.existing_aggregation_info()
+            }
+            TaskMetaStateReadGuard::Partial(state) => {
+                state.aggregation_leaf.existing_aggregation_info()
+            }
+            TaskMetaStateReadGuard::Unloaded => None,
+        }
+        .map(|info| {
+            let info = info.lock();
+            (info.generation(), info.data.clone())
+        })
+    }
+
+    /// Writes the aggregation structure of this task. Only for debugging.
+    pub(crate) fn dump_aggregation(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        let fmt_info = |info: &AggregatedInfo| info.summary();
+        match self.state() {
+            TaskMetaStateReadGuard::Full(state) => {
+                writeln!(
+                    out,
+                    "{} ({})",
+                    self.get_description(),
+                    Task::state_string(&state)
+                )?;
+                state.aggregation_leaf.dump(out, &fmt_info)
+            }
+            TaskMetaStateReadGuard::Partial(state) => {
+                writeln!(out, "{} (partial)", self.get_description())?;
+                state.aggregation_leaf.dump(out, &fmt_info)
+            }
+            TaskMetaStateReadGuard::Unloaded => {
+                writeln!(out, "{} (unloaded)", self.get_description())
+            }
+        }
+    }
+
     pub(crate) fn get_description(&self) -> String {
         Self::format_description(&TaskTypeForDescription::from(&self.ty), self.id)
     }
@@ -631,6 +681,7 @@ impl Task {
                 let aggregation = aggregation_context.aggregation_info(task);
                 aggregation
                     .lock()
+                    .make_mut()
                     .remove_collectible_dependent_task(trait_type, reader);
             }
         }
@@ -875,12 +926,7 @@ impl Task {
             let outdated_children = take(outdated_children);
             let outdated_collectibles = outdated_collectibles.take_collectibles();
 
-            let mut change = TaskChange {
-                unfinished: -1,
-                #[cfg(feature = "track_unfinished")]
-                unfinished_tasks_update: vec![(self.id, -1)],
-                ..Default::default()
-            };
+            let mut change = TaskChange::unfinished(self.id, -1);
             if let Some(collectibles) = outdated_collectibles {
                 for ((trait_type, value), count) in collectibles.into_iter() {
                     change.collectibles.push((trait_type, value, -count));
@@ -1003,12 +1049,7 @@ impl Task {
                         state.stateful = stateful;
                         state.state_type = Done { dependencies };
                         if !count_as_finished {
-                            let mut change = TaskChange {
-                                unfinished: -1,
-                                #[cfg(feature = "track_unfinished")]
-                                unfinished_tasks_update: vec![(self.id, -1)],
-                                ..Default::default()
-                            };
+                            let mut change = TaskChange::unfinished(self.id, -1);
                             if let Some(collectibles) = outdated_collectibles {
                                 for ((trait_type, value), count) in collectibles.into_iter() {
                                     change.collectibles.push((trait_type, value, -count));
@@ -1062,6 +1103,7 @@ impl Task {
             aggregation_context
                 .aggregation_info(self.id)
                 .lock()
+                .make_mut()
                 .root_type = None;
         }
         aggregation_context.apply_queued_updates();
@@ -1141,11 +1183,8 @@ impl Task {
                             state.aggregation_leaf.change(
                                 &aggregation_context,
                                 &TaskChange {
-                                    unfinished: 1,
-                                    #[cfg(feature = "track_unfinished")]
-                                    unfinished_tasks_update: vec![(self.id, 1)],
                                     dirty_tasks_update: vec![(self.id, 1)],
-                                    ..Default::default()
+                                    ..TaskChange::unfinished(self.id, 1)
                                 },
                             );
                             has_set_unfinished = true;
@@ -1163,15 +1202,9 @@ impl Task {
                             }
                         };
                     if !has_set_unfinished {
-                        state.aggregation_leaf.change(
-                            &aggregation_context,
-                            &TaskChange {
-                                unfinished: 1,
-                                #[cfg(feature = "track_unfinished")]
-                                unfinished_tasks_update: vec![(self.id, 1)],
-                                ..Default::default()
-                            },
-                        );
+                        state
+                            .aggregation_leaf
+                            .change(&aggregation_context, &TaskChange::unfinished(self.id, 1));
                     }
                     if should_schedule {
                         state.state_type = Scheduled {
@@ -1208,12 +1241,7 @@ impl Task {
                     let outdated_children = take(outdated_children);
                     let outdated_collectibles = outdated_collectibles.take_collectibles();
                     let change = if count_as_finished {
-                        let mut change = TaskChange {
-                            unfinished: 1,
-                            #[cfg(feature = "track_unfinished")]
-                            unfinished_tasks_update: vec![(self.id, 1)],
-                            ..Default::default()
-                        };
+                        let mut change = TaskChange::unfinished(self.id, 1);
                         if let Some(collectibles) = outdated_collectibles {
                             for ((trait_type, value), count) in collectibles.into_iter() {
                                 change.collectibles.push((trait_type, value, -count));
@@ -1251,12 +1279,14 @@ impl Task {
         aggregation_context.apply_queued_updates();
     }
 
+    /// Schedules the task when it's still dirty. Returns the change of the
+    /// dirty count, which is prepared under the task lock and needs to be
+    /// applied by the caller after that.
     pub(crate) fn schedule_when_dirty_from_aggregation(
         &self,
-        backend: &MemoryBackend,
+        aggregation_context: &TaskAggregationContext,
         turbo_tasks: &dyn TurboTasksBackendApi<MemoryBackend>,
-    ) {
-        let aggregation_context = TaskAggregationContext::new(turbo_tasks, backend);
+    ) -> Option<PreparedChange<AggregatedInfo, TaskId, TaskChange>> {
         let mut state = self.full_state_mut();
         if let TaskStateType::Dirty {
             ref mut event,
@@ -1267,8 +1297,8 @@ impl Task {
                 event: event.take(),
                 outdated_dependencies: take(outdated_dependencies),
             };
-            let job = state.aggregation_leaf.change_job(
-                &aggregation_context,
+            let prepared = state.aggregation_leaf.prepare_change(
+                aggregation_context,
                 TaskChange {
                     dirty_tasks_update: vec![(self.id, -1)],
                     ..Default::default()
@@ -1276,7 +1306,9 @@ impl Task {
             );
             drop(state);
             turbo_tasks.schedule(self.id);
-            job();
+            Some(prepared)
+        } else {
+            None
         }
     }
 
@@ -1550,7 +1582,12 @@ impl Task {
                             TaskMetaStateReadGuard::Unloaded => false,
                         };
                         if active {
-                            child.schedule_when_dirty_from_aggregation(backend, turbo_tasks);
+                            if let Some(prepared) = child.schedule_when_dirty_from_aggregation(
+                                &aggregation_context,
+                                turbo_tasks,
+                            ) {
+                                prepared.apply(&aggregation_context);
+                            }
                         }
                     }
                 });
@@ -1593,7 +1630,7 @@ impl Task {
                     aggregation.root_type,
                     Some(RootType::ReadingStronglyConsistent)
                 ) {
-                    aggregation.root_type = None;
+                    aggregation.make_mut().root_type = None;
                 }
             }
         }
@@ -1648,6 +1685,7 @@ impl Task {
         aggregation_context
             .aggregation_info(id)
             .lock()
+            .make_mut()
             .read_collectibles(trait_type, reader)
     }
 
@@ -2013,9 +2051,8 @@ impl Task {
                 aggregation_leaf.change(
                     &TaskAggregationContext::new(turbo_tasks, backend),
                     &TaskChange {
-                        unfinished: 1,
                         dirty_tasks_update: vec![(self.id, 1)],
-                        ..Default::default()
+                        ..TaskChange::unfinished(self.id, 1)
                     },
                 );
                 if aggregation_context.take_scheduled_dirty_task(self.id) {
@@ -2024,9 +2061,8 @@ impl Task {
                     aggregation_leaf.change(
                         &TaskAggregationContext::new(turbo_tasks, backend),
                         &TaskChange {
-                            unfinished: -1,
                             dirty_tasks_update: vec![(self.id, -1)],
-                            ..Default::default()
+                            ..TaskChange::unfinished(self.id, -1)
                         },
                     );
                     return false;
@@ -2080,28 +2116,20 @@ impl Task {
 
         let aggregation_context = TaskAggregationContext::new(turbo_tasks, backend);
 
-        // Remove all children, as they will be added again when this task is executed
-        // again
-        if !children.is_empty() {
-            for child in children {
-                aggregation_leaf.remove_child(&aggregation_context, &child);
-            }
-        }
-
-        // Remove all collectibles, as they will be added again when this task is
-        // executed again.
-        if let Some(collectibles) = collectibles.into_inner() {
-            aggregation_leaf.change(
-                &aggregation_context,
-                &TaskChange {
-                    collectibles: collectibles
-                        .into_iter()
-                        .map(|((t, r), c)| (t, r, -c))
-                        .collect(),
-                    ..Default::default()
-                },
-            );
-        }
+        // Remove all children and collectibles, as they will be added again when this
+        // task is executed again.
+        let remove_change = collectibles
+            .into_inner()
+            .map(|collectibles| TaskChange {
+                collectibles: collectibles
+                    .into_iter()
+                    .map(|((t, r), c)| (t, r, -c))
+                    .collect(),
+                ..Default::default()
+            })
+            .filter(|change| !change.is_empty());
+        let remove_job =
+            aggregation_leaf.remove_all_job(&aggregation_context, remove_change, children);
 
         // TODO aggregation_leaf
         let unset = !aggregation_leaf.has_upper();
@@ -2119,6 +2147,7 @@ impl Task {
             }));
         }
         drop(state);
+        remove_job();
 
         // Notify everyone that is listening on our output or cells.
         // This will mark everyone as dirty and will trigger a new execution when they
@@ -2,6 +2,7 @@ use std::{
     borrow::Cow,
     hash::{BuildHasher, Hash},
     mem::take,
+    ops::{Deref, DerefMut},
 };
 
 use auto_hash_map::{map::Entry, AutoMap};
@@ -12,19 +13,20 @@ use turbo_tasks::{event::Event, RawVc, TaskId, TaskIdSet, TraitTypeId, TurboTask
 use super::{meta_state::TaskMetaStateWriteGuard, TaskStateType};
 use crate::{
     aggregation_tree::{
-        aggregation_info, AggregationContext, AggregationInfoReference, AggregationItemLock,
-        AggregationTreeLeaf,
+        aggregation_info, assert_not_applying_change, AggregationContext, AggregationInfoReference,
+        AggregationItemLock, AggregationTreeLeaf, CowInfo,
     },
     MemoryBackend,
 };
 
+#[derive(Clone, Copy)]
 pub enum RootType {
     Once,
     Root,
     ReadingStronglyConsistent,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct CollectiblesInfo {
     collectibles: AutoMap<RawVc, i32>,
     dependent_tasks: TaskIdSet,
@@ -40,13 +42,48 @@ pub enum RootInfoType {
     IsActive,
 }
 
+/// The info stored in the aggregation tree. The aggregated data is stored as
+/// [CowInfo], so snapshots of it only copy a pointer. The event is not part of
+/// a snapshot.
+pub struct AggregatedInfo {
+    pub data: CowInfo<Aggregated>,
+    /// Event that will be notified when all unfinished tasks are done.
+    pub unfinished_event: Event,
+}
+
+impl Default for AggregatedInfo {
+    fn default() -> Self {
+        Self {
+            data: CowInfo::default(),
+            unfinished_event: Event::new(|| "Aggregated::unfinished_event".to_string()),
+        }
+    }
+}
+
+impl Deref for AggregatedInfo {
+    type Target = CowInfo<Aggregated>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.data
+    }
+}
+
+impl DerefMut for AggregatedInfo {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.data
+    }
+}
+
+#[derive(Clone)]
 pub struct Aggregated {
     /// The number of unfinished items in the lower aggregation level.
     /// Unfinished means not "Done".
-    // TODO determine if this can go negative in concurrent situations.
+    // This can be negative temporarily in a bottom tree which an item has been
+    // moved away from before a pending change of the item has been applied, see
+    // PreparedChange. Only transitions between zero and a positive count are
+    // propagated, and the new bottom tree of the item is added to the uppers
+    // before it's removed from the old one. So the uppers never miss it.
     pub unfinished: i32,
-    /// Event that will be notified when all unfinished tasks are done.
-    pub unfinished_event: Event,
     /// A list of all tasks that are unfinished. Only for debugging.
     #[cfg(feature = "track_unfinished")]
     pub unfinished_tasks: AutoMap<TaskId, i32, BuildNoHashHasher<TaskId>>,
@@ -70,7 +107,6 @@ impl Default for Aggregated {
     fn default() -> Self {
         Self {
             unfinished: 0,
-            unfinished_event: Event::new(|| "Aggregated::unfinished_event".to_string()),
             #[cfg(feature = "track_unfinished")]
             unfinished_tasks: AutoMap::with_hasher(),
             dirty_tasks: AutoMap::with_hasher(),
@@ -81,6 +117,23 @@ impl Default for Aggregated {
 }
 
 impl Aggregated {
+    /// A short human readable summary of the aggregated info. Only for
+    /// debugging.
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "unfinished: {}, dirty tasks: {}, collectibles: {}, root: {}",
+            self.unfinished,
+            self.dirty_tasks.len(),
+            self.collectibles.len(),
+            match self.root_type {
+                None => "none",
+                Some(RootType::Once) => "once",
+                Some(RootType::Root) => "root",
+                Some(RootType::ReadingStronglyConsistent) => "reading strongly consistent",
+            }
+        )
+    }
+
     pub(crate) fn remove_collectible_dependent_task(
         &mut self,
         trait_type: TraitTypeId,
@@ -126,6 +179,18 @@ pub struct TaskChange {
 }
 
 impl TaskChange {
+    /// A change of a single task becoming unfinished (`count` = 1) or finished
+    /// (`count` = -1).
+    #[allow(unused_variables, reason = "feature flag")]
+    pub fn unfinished(task: TaskId, count: i32) -> Self {
+        Self {
+            unfinished: count,
+            #[cfg(feature = "track_unfinished")]
+            unfinished_tasks_update: vec![(task, count)],
+            ..Default::default()
+        }
+    }
+
     pub fn is_empty(&self) -> bool {
         #[allow(unused_mut, reason = "feature flag")]
         let mut empty = self.unfinished == 0
@@ -191,7 +256,7 @@ impl<'a> TaskAggregationContext<'a> {
         }
     }
 
-    pub fn aggregation_info(&self, id: TaskId) -> AggregationInfoReference<Aggregated> {
+    pub fn aggregation_info(&self, id: TaskId) -> AggregationInfoReference<AggregatedInfo> {
         aggregation_info(self, &id)
     }
 }
@@ -216,13 +281,14 @@ impl<'a> Drop for TaskAggregationContext<'a> {
 
 impl<'a> AggregationContext for TaskAggregationContext<'a> {
     type ItemLock<'l> = TaskGuard<'l> where Self: 'l;
-    type Info = Aggregated;
+    type Info = AggregatedInfo;
     type ItemChange = TaskChange;
     type ItemRef = TaskId;
     type RootInfo = bool;
     type RootInfoType = RootInfoType;
 
     fn item<'b>(&'b self, reference: &TaskId) -> Self::ItemLock<'b> {
+        assert_not_applying_change();
         let task = self.backend.task(*reference);
         TaskGuard {
             id: *reference,
@@ -232,14 +298,19 @@ impl<'a> AggregationContext for TaskAggregationContext<'a> {
 
     fn apply_change(
         &self,
-        info: &mut Aggregated,
+        info: &mut AggregatedInfo,
         change: &Self::ItemChange,
     ) -> Option<Self::ItemChange> {
+        let AggregatedInfo {
+            data,
+            unfinished_event,
+        } = info;
+        let info = data.make_mut();
         let mut unfinished = 0;
         if info.unfinished > 0 {
             info.unfinished += change.unfinished;
             if info.unfinished <= 0 {
-                info.unfinished_event.notify(usize::MAX);
+                unfinished_event.notify(usize::MAX);
                 unfinished = -1;
             }
         } else {
@@ -286,9 +357,13 @@ impl<'a> AggregationContext for TaskAggregationContext<'a> {
                 }
             }
         }
+        // The counts are only consistent when none of them is negative
+        // temporarily, see `Aggregated::unfinished`.
         #[cfg(feature = "track_unfinished")]
-        if info.unfinished > 0 && info.unfinished_tasks.is_empty()
-            || info.unfinished == 0 && !info.unfinished_tasks.is_empty()
+        if info.unfinished >= 0
+            && info.unfinished_tasks.values().all(|&count| count >= 0)
+            && (info.unfinished > 0 && info.unfinished_tasks.is_empty()
+                || info.unfinished == 0 && !info.unfinished_tasks.is_empty())
         {
             panic!(
                 "inconsistent state: unfinished {}, unfinished_tasks {:?}, change {:?}",
@@ -309,7 +384,29 @@ impl<'a> AggregationContext for TaskAggregationContext<'a> {
         }
     }
 
-    fn info_to_add_change(&self, info: &Aggregated) -> Option<Self::ItemChange> {
+    fn merge_change(
+        &self,
+        change: &mut Self::ItemChange,
+        other: Self::ItemChange,
+    ) -> Option<Self::ItemChange> {
+        let TaskChange {
+            unfinished,
+            #[cfg(feature = "track_unfinished")]
+            unfinished_tasks_update,
+            dirty_tasks_update,
+            collectibles,
+        } = other;
+        change.unfinished += unfinished;
+        #[cfg(feature = "track_unfinished")]
+        change
+            .unfinished_tasks_update
+            .extend(unfinished_tasks_update);
+        change.dirty_tasks_update.extend(dirty_tasks_update);
+        change.collectibles.extend(collectibles);
+        None
+    }
+
+    fn info_to_add_change(&self, info: &AggregatedInfo) -> Option<Self::ItemChange> {
         let mut change = TaskChange::default();
         if info.unfinished > 0 {
             change.unfinished = 1;
@@ -335,7 +432,7 @@ impl<'a> AggregationContext for TaskAggregationContext<'a> {
         }
     }
 
-    fn info_to_remove_change(&self, info: &Aggregated) -> Option<Self::ItemChange> {
+    fn info_to_remove_change(&self, info: &AggregatedInfo) -> Option<Self::ItemChange> {
         let mut change = TaskChange::default();
         if info.unfinished > 0 {
             change.unfinished = -1;
@@ -367,7 +464,7 @@ impl<'a> AggregationContext for TaskAggregationContext<'a> {
 
     fn info_to_root_info(
         &self,
-        info: &Aggregated,
+        info: &AggregatedInfo,
         root_info_type: &RootInfoType,
     ) -> Self::RootInfo {
         match root_info_type {
@@ -395,7 +492,7 @@ pub struct TaskGuard<'l> {
 }
 
 impl<'l> AggregationItemLock for TaskGuard<'l> {
-    type Info = Aggregated;
+    type Info = AggregatedInfo;
     type ItemRef = TaskId;
     type ItemChange = TaskChange;
     type ChildrenIter<'a> = impl Iterator<Item = Cow<'a, TaskId>> + 'a where Self: 'a;
@@ -472,9 +569,7 @@ impl<'l> AggregationItemLock for TaskGuard<'l> {
                             ..
                         }
                 ) {
-                    change.unfinished = 1;
-                    #[cfg(feature = "track_unfinished")]
-                    change.unfinished_tasks_update.push((self.id, 1));
+                    change = TaskChange::unfinished(self.id, 1);
                 }
                 if matches!(guard.state_type, TaskStateType::Dirty { .. }) {
                     change.dirty_tasks_update.push((self.id, 1));
@@ -517,9 +612,7 @@ impl<'l> AggregationItemLock for TaskGuard<'l> {
                             ..
                         }
                 ) {
-                    change.unfinished = -1;
-                    #[cfg(feature = "track_unfinished")]
-                    change.unfinished_tasks_update.push((self.id, -1));
+                    change = TaskChange::unfinished(self.id, -1);
                 }
                 if matches!(guard.state_type, TaskStateType::Dirty { .. }) {
                     change.dirty_tasks_update.push((self.id, -1));
@@ -551,7 +644,7 @@ impl<'l> AggregationItemLock for TaskGuard<'l> {
     }
 }
 
-pub type TaskAggregationTreeLeaf = AggregationTreeLeaf<Aggregated, TaskId>;
+pub type TaskAggregationTreeLeaf = AggregationTreeLeaf<AggregatedInfo, TaskId>;
 
 fn update_count_entry<K: Eq + Hash, H: BuildHasher + Default, const I: usize>(
     entry: Entry<'_, K, i32, H, I>,
@@ -0,0 +1,103 @@
+#![feature(arbitrary_self_types)]
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::Result;
+use turbo_tasks::{Completion, TurboTasks, Vc};
+use turbo_tasks_memory::MemoryBackend;
+use turbo_tasks_testing::register;
+
+register!();
+
+static DUMP: OnceLock<(Arc<TurboTasks<MemoryBackend>>, PathBuf)> = OnceLock::new();
+
+#[allow(clippy::no_effect)] // for *REGISTER
+#[test]
+fn dump_aggregation_structure() {
+    *REGISTER;
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let tt = TurboTasks::new(MemoryBackend::default());
+        let task = tt.spawn_once_task(async move {
+            chain(10).strongly_consistent().await?;
+            Ok(Vc::<()>::default())
+        });
+        tt.wait_task_completion(task, false).await.unwrap();
+
+        let path = std::env::temp_dir().join(format!(
+            "turbo-tasks-aggregation-dump-{}.txt",
+            std::process::id()
+        ));
+        tt.backend().dump_aggregation_structure(&path).unwrap();
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (structure, roots) = dump.split_once("aggregation roots").unwrap();
+        assert_eq!(structure.matches("chain").count(), 11);
+        assert!(structure.contains("top tree (depth 0)"));
+        // The strongly consistent read has aggregated the outermost chain task
+        assert!(roots.starts_with(" (1):"));
+        assert!(roots.contains("chain (generation "));
+        assert!(roots.contains("): unfinished: 0"));
+    })
+}
+
+#[allow(clippy::no_effect)] // for *REGISTER
+#[test]
+fn dump_while_running() {
+    *REGISTER;
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    rt.block_on(async {
+        let tt = TurboTasks::new(MemoryBackend::default());
+        let path = std::env::temp_dir().join(format!(
+            "turbo-tasks-aggregation-dump-running-{}.txt",
+            std::process::id()
+        ));
+        assert!(DUMP.set((tt.clone(), path.clone())).is_ok());
+        let task = tt.spawn_once_task(async move {
+            dumping_chain(3).await?;
+            Ok(Vc::<()>::default())
+        });
+        tt.wait_task_completion(task, false).await.unwrap();
+
+        let dump = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // The dump was written by the innermost task, while the whole chain was
+        // still running
+        let (structure, _) = dump.split_once("aggregation roots").unwrap();
+        let tasks = structure
+            .lines()
+            .filter(|line| line.contains("dumping_chain"))
+            .collect::<Vec<_>>();
+        assert_eq!(tasks.len(), 4);
+        assert!(tasks.iter().all(|line| line.ends_with("(in progress)")));
+    })
+}
+
+#[turbo_tasks::function]
+async fn dumping_chain(n: u32) -> Result<Vc<Completion>> {
+    if n > 0 {
+        dumping_chain(n - 1).await?;
+    } else {
+        let (tt, path) = DUMP.get().unwrap();
+        tt.backend().dump_aggregation_structure(path)?;
+    }
+    Ok(Completion::new())
+}
+
+#[turbo_tasks::function]
+async fn chain(n: u32) -> Result<Vc<Completion>> {
+    if n > 0 {
+        chain(n - 1).await?;
+    }
+    Ok(Completion::new())
+}